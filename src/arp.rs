@@ -0,0 +1,412 @@
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fmt::{self, Display};
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::unix::prelude::{AsRawFd, FromRawFd};
+
+use crate::plot_data::{Outcome, Sample};
+use crate::ui;
+use log::{debug, error, info, warn};
+use nix::{ifaddrs::getifaddrs, libc};
+use socket2::Socket;
+use tokio::{
+    io::{unix::AsyncFd, Interest},
+    sync::{mpsc::Sender, Mutex, RwLock},
+    time::{interval, sleep, Duration, Instant},
+};
+
+/// Ethernet and IPv4 ARP packet without the link layer header, see RFC 826.
+const PACKET_SIZE: usize = 28;
+const ETH_P_ARP: u16 = 0x0806;
+const ETH_P_IP: u16 = 0x0800;
+const HTYPE_ETHERNET: u16 = 1;
+const OPER_REQUEST: u16 = 1;
+const OPER_REPLY: u16 = 2;
+const BROADCAST: MacAddr = MacAddr([0xff; 6]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let octets: Vec<String> = self.0.iter().map(|b| format!("{b:02x}")).collect();
+        write!(f, "{}", octets.join(":"))
+    }
+}
+
+fn read_u16(buf: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([buf[at], buf[at + 1]])
+}
+
+fn read_ipv4(buf: &[u8], at: usize) -> Ipv4Addr {
+    Ipv4Addr::new(buf[at], buf[at + 1], buf[at + 2], buf[at + 3])
+}
+
+/// Ask who has `target`, telling it we are `mac` at `source`.
+pub fn request(mac: MacAddr, source: Ipv4Addr, target: Ipv4Addr) -> [u8; PACKET_SIZE] {
+    let mut packet = [0; PACKET_SIZE];
+    packet[0..2].copy_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+    packet[2..4].copy_from_slice(&ETH_P_IP.to_be_bytes());
+    packet[4] = 6;
+    packet[5] = 4;
+    packet[6..8].copy_from_slice(&OPER_REQUEST.to_be_bytes());
+    packet[8..14].copy_from_slice(&mac.0);
+    packet[14..18].copy_from_slice(&source.octets());
+    // the target hardware address stays zero, that's what we ask for
+    packet[24..28].copy_from_slice(&target.octets());
+    packet
+}
+
+/// Sender of an ARP reply, `None` for anything else.
+pub fn parse_reply(buf: &[u8]) -> Option<(MacAddr, Ipv4Addr)> {
+    if buf.len() < PACKET_SIZE
+        || read_u16(buf, 0) != HTYPE_ETHERNET
+        || read_u16(buf, 2) != ETH_P_IP
+        || read_u16(buf, 6) != OPER_REPLY
+    {
+        return None;
+    }
+    Some((MacAddr(buf[8..14].try_into().unwrap()), read_ipv4(buf, 14)))
+}
+
+/// The request a reply received `now` answers. ARP has no sequence numbers, so
+/// that is the latest request, but only while it has not timed out yet.
+fn answered(starts: &[Instant], now: Instant, timeout: Duration) -> Option<usize> {
+    let seq = starts.len().checked_sub(1)?;
+    match now.duration_since(starts[seq]) < timeout {
+        true => Some(seq),
+        false => None,
+    }
+}
+
+/// Count a reply from `mac`, true if another MAC address answered before.
+fn count_reply(macs: &mut BTreeMap<MacAddr, usize>, mac: MacAddr) -> bool {
+    let duplicate = !macs.contains_key(&mac) && !macs.is_empty();
+    *macs.entry(mac).or_insert(0) += 1;
+    duplicate
+}
+
+/// Index, hardware address and first IPv4 address of `interface`.
+/// Without an address requests are sent from 0.0.0.0, like an RFC 5227 probe.
+fn interface(interface: &str) -> io::Result<(libc::c_int, MacAddr, Ipv4Addr)> {
+    let name = CString::new(interface)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"))?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let address = std::fs::read_to_string(format!("/sys/class/net/{interface}/address"))?;
+    let octets: Vec<u8> = address
+        .trim()
+        .split(':')
+        .filter_map(|octet| u8::from_str_radix(octet, 16).ok())
+        .collect();
+    let mac = match octets.try_into() {
+        Ok(octets) => MacAddr(octets),
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{interface} has no ethernet address"),
+            ))
+        }
+    };
+    let source = getifaddrs()?
+        .filter(|ifaddr| ifaddr.interface_name == interface)
+        .find_map(|ifaddr| Some(ifaddr.address?.as_sockaddr_in()?.ip()))
+        .map(Ipv4Addr::from)
+        .unwrap_or(Ipv4Addr::UNSPECIFIED);
+    Ok((index as libc::c_int, mac, source))
+}
+
+/// Link layer address of `mac` on interface `index`, for ARP packages.
+fn link_addr(index: libc::c_int, mac: MacAddr) -> libc::sockaddr_ll {
+    let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_protocol = ETH_P_ARP.to_be();
+    addr.sll_ifindex = index;
+    addr.sll_halen = 6;
+    addr.sll_addr[..6].copy_from_slice(&mac.0);
+    addr
+}
+
+/// Sends ARP requests for an on-link address and measures how long replies take.
+/// Every MAC address which answers is counted, more than one means the address
+/// is used twice.
+#[derive(Debug)]
+pub struct ArpPinger {
+    socket: AsyncFd<Socket>,
+    index: libc::c_int,
+    mac: MacAddr,
+    source: Ipv4Addr,
+    target: Ipv4Addr,
+    count: u16,
+    timeout: Duration,
+    interval: Duration,
+    starts: RwLock<Vec<Instant>>,
+    pub latencies: Mutex<Vec<Option<Duration>>>,
+    /// Replies of every MAC address which answered.
+    pub macs: Mutex<BTreeMap<MacAddr, usize>>,
+    tx: Sender<Sample>,
+}
+
+impl ArpPinger {
+    pub fn new(
+        target: Ipv4Addr,
+        interface_name: &str,
+        count: u16,
+        timeout: Duration,
+        interval: Duration,
+        tx: Sender<Sample>,
+    ) -> io::Result<Self> {
+        let (index, mac, source) = interface(interface_name)?;
+        // a datagram packet socket, the kernel adds the ethernet header
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_PACKET,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                ETH_P_ARP.to_be() as libc::c_int,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        let addr = link_addr(index, MacAddr([0; 6]));
+        let result = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of_val(&addr) as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        socket.set_nonblocking(true)?;
+        info!("ARPING {target} from {source} {interface_name} ({mac})");
+        Ok(ArpPinger {
+            // the descriptor is closed only by dropping the socket
+            socket: unsafe { AsyncFd::register(socket)? },
+            index,
+            mac,
+            source,
+            target,
+            count,
+            timeout,
+            interval,
+            starts: Default::default(),
+            latencies: Default::default(),
+            macs: Default::default(),
+            tx,
+        })
+    }
+    pub async fn start(&'static self) {
+        let listen = tokio::spawn(self.listen());
+        let mut timer = if self.interval.is_zero() {
+            interval(Duration::from_nanos(1))
+        } else {
+            interval(self.interval)
+        };
+        let packet = request(self.mac, self.source, self.target);
+        let mut last_timeout = None;
+        let to = link_addr(self.index, BROADCAST);
+        for seq in 0..self.count {
+            if ui::wait_resumed().await {
+                timer.reset();
+            }
+            timer.tick().await;
+            // listen looks up the latency of every request it knows the start of
+            self.latencies.lock().await.push(None);
+            self.starts.write().await.push(Instant::now());
+            let sent = self
+                .socket
+                .async_io(Interest::WRITABLE, |socket| {
+                    let n = unsafe {
+                        libc::sendto(
+                            socket.as_raw_fd(),
+                            packet.as_ptr() as *const libc::c_void,
+                            packet.len(),
+                            0,
+                            &to as *const _ as *const libc::sockaddr,
+                            mem::size_of_val(&to) as libc::socklen_t,
+                        )
+                    };
+                    match n {
+                        n if n < 0 => Err(io::Error::last_os_error()),
+                        n => Ok(n),
+                    }
+                })
+                .await;
+            match sent {
+                Ok(_) => debug!("Sent request {seq} for {}", self.target),
+                Err(e) => error!("Failed to send packet: {}", e),
+            }
+            last_timeout = Some(tokio::spawn(self.timeout(seq)));
+        }
+        // the last request times out last, the listener is needed until then
+        if let Some(last_timeout) = last_timeout {
+            last_timeout.await.unwrap();
+        }
+        listen.abort();
+    }
+    async fn listen(&'static self) {
+        let mut buf = vec![0; 1500];
+        loop {
+            let received = self
+                .socket
+                .async_io(Interest::READABLE, |socket| {
+                    let n = unsafe {
+                        libc::recv(
+                            socket.as_raw_fd(),
+                            buf.as_mut_ptr() as *mut libc::c_void,
+                            buf.len(),
+                            0,
+                        )
+                    };
+                    match n {
+                        n if n < 0 => Err(io::Error::last_os_error()),
+                        n => Ok(n as usize),
+                    }
+                })
+                .await;
+            let n = match received {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Stopped receiving: {}", e);
+                    return;
+                }
+            };
+            let (mac, sender) = match parse_reply(&buf[..n]) {
+                Some(reply) if reply.1 == self.target => reply,
+                _ => continue,
+            };
+            // decided while holding the latencies, so a reply is either
+            // in time or its request is reported as timed out, never both
+            let mut latencies = self.latencies.lock().await;
+            let starts = self.starts.read().await;
+            let now = Instant::now();
+            let seq = match answered(&starts, now, self.timeout) {
+                Some(seq) => seq,
+                None => {
+                    debug!("Ignoring late reply from {sender} [{mac}]");
+                    continue;
+                }
+            };
+            let duration = now.duration_since(starts[seq]);
+            drop(starts);
+            if count_reply(&mut *self.macs.lock().await, mac) {
+                warn!("{sender} is also used by {mac}, duplicate address");
+            }
+            info!("Reply #{seq} from {sender} [{mac}] in {:?}", duration);
+            if latencies[seq].is_none() {
+                latencies[seq] = Some(duration);
+                let sample = Sample::new(duration, Outcome::Reply(duration));
+                let _ = self.tx.send(sample).await;
+            }
+        }
+    }
+    async fn timeout(&'static self, seq: u16) {
+        sleep(self.timeout).await;
+        if self.latencies.lock().await[seq as usize].is_none() {
+            error!("Timeout for request {seq}");
+            let elapsed = self.starts.read().await[seq as usize].elapsed();
+            let _ = self.tx.send(Sample::new(elapsed, Outcome::Timeout)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 1]);
+    const OTHER: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 2]);
+
+    /// What a host at 192.0.2.1 answers to `request`.
+    fn reply(request: &[u8], mac: MacAddr) -> Vec<u8> {
+        let mut reply = request.to_vec();
+        reply[6..8].copy_from_slice(&OPER_REPLY.to_be_bytes());
+        reply[18..28].copy_from_slice(&request[8..18]);
+        reply[8..14].copy_from_slice(&mac.0);
+        reply[14..18].copy_from_slice(&request[24..28]);
+        reply
+    }
+
+    #[test]
+    fn request_asks_for_target() {
+        let packet = request(
+            MAC,
+            Ipv4Addr::new(192, 0, 2, 2),
+            Ipv4Addr::new(192, 0, 2, 1),
+        );
+        assert_eq!(
+            packet,
+            [
+                0, 1, 8, 0, 6, 4, 0, 1, 2, 0, 0, 0, 0, 1, 192, 0, 2, 2, 0, 0, 0, 0, 0, 0, 192, 0,
+                2, 1
+            ]
+        );
+        // our own request is no reply
+        assert_eq!(parse_reply(&packet), None);
+    }
+
+    #[test]
+    fn parse_reply_reads_sender() {
+        let packet = request(
+            MAC,
+            Ipv4Addr::new(192, 0, 2, 2),
+            Ipv4Addr::new(192, 0, 2, 1),
+        );
+        let reply = reply(&packet, OTHER);
+        assert_eq!(
+            parse_reply(&reply),
+            Some((OTHER, Ipv4Addr::new(192, 0, 2, 1)))
+        );
+        // ethernet pads short frames
+        let mut padded = reply.clone();
+        padded.resize(46, 0);
+        assert_eq!(parse_reply(&padded), parse_reply(&reply));
+        assert_eq!(parse_reply(&reply[..PACKET_SIZE - 1]), None);
+        let mut other_protocol = reply;
+        other_protocol[2..4].copy_from_slice(&0x86ddu16.to_be_bytes());
+        assert_eq!(parse_reply(&other_protocol), None);
+    }
+
+    #[test]
+    fn answered_is_latest_request_in_time() {
+        let timeout = Duration::from_secs(1);
+        let start = Instant::now();
+        assert_eq!(answered(&[], start, timeout), None);
+        let starts = [start, start + Duration::from_millis(1500)];
+        assert_eq!(
+            answered(&starts[..1], start + Duration::from_millis(999), timeout),
+            Some(0)
+        );
+        // the first request timed out before the second was sent
+        assert_eq!(
+            answered(&starts[..1], start + Duration::from_millis(1200), timeout),
+            None
+        );
+        assert_eq!(
+            answered(&starts, start + Duration::from_millis(1600), timeout),
+            Some(1)
+        );
+        assert_eq!(
+            answered(&starts, start + Duration::from_secs(3), timeout),
+            None
+        );
+    }
+
+    #[test]
+    fn count_reply_finds_duplicates() {
+        let mut macs = BTreeMap::new();
+        assert!(!count_reply(&mut macs, MAC));
+        assert!(!count_reply(&mut macs, MAC));
+        assert!(count_reply(&mut macs, OTHER));
+        assert!(!count_reply(&mut macs, OTHER));
+        assert_eq!(macs, BTreeMap::from([(MAC, 2), (OTHER, 2)]));
+    }
+}
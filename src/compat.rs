@@ -0,0 +1,212 @@
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use dns_lookup::lookup_addr;
+
+/// Print what another tool prints, for scripts parsing its output.
+#[derive(Clone, Copy, ValueEnum, Debug, PartialEq, Eq)]
+pub enum Compat {
+    /// iputils ping, and traceroute for `trace`
+    Iputils,
+}
+
+/// Reverse lookup of `addr`, `None` without a PTR record.
+pub fn reverse_name(addr: Ipv4Addr) -> Option<String> {
+    lookup_addr(&addr.into())
+        .ok()
+        .filter(|name| *name != addr.to_string())
+}
+
+/// `name (addr)`, or only the address if it has no name.
+pub fn format_host(addr: Ipv4Addr, name: Option<&str>) -> String {
+    match name {
+        Some(name) => format!("{name} ({addr})"),
+        None => addr.to_string(),
+    }
+}
+
+/// Round trip in milliseconds with iputils' precision, which shrinks as the time grows.
+/// iputils works on whole microseconds, so does this.
+pub fn format_time(rtt: Duration) -> String {
+    let micros = rtt.as_micros();
+    if micros >= 99_950 {
+        format!("{}", (micros + 500) / 1000)
+    } else if micros >= 9_995 {
+        let micros = micros + 50;
+        format!("{}.{:01}", micros / 1000, micros % 1000 / 100)
+    } else if micros >= 1000 {
+        let micros = micros + 5;
+        format!("{}.{:02}", micros / 1000, micros % 1000 / 10)
+    } else {
+        format_millis(micros)
+    }
+}
+
+fn format_millis(micros: u128) -> String {
+    format!("{}.{:03}", micros / 1000, micros % 1000)
+}
+
+/// A number like printf's `%g`: six significant digits without trailing zeros.
+fn format_g(value: f64) -> String {
+    if value == 0.0 {
+        return "0".to_string();
+    }
+    let digits = value.abs().log10().floor() as i32 + 1;
+    let decimals = (6 - digits).max(0) as usize;
+    let formatted = format!("{value:.decimals$}");
+    match formatted.contains('.') {
+        true => formatted
+            .trim_end_matches('0')
+            .trim_end_matches('.')
+            .to_string(),
+        false => formatted,
+    }
+}
+
+/// Round trips summed up in microseconds, like iputils keeps them.
+#[derive(Debug, Default)]
+pub struct RttTotals {
+    count: u128,
+    sum: u128,
+    squares: u128,
+    min: Option<u128>,
+    max: u128,
+}
+
+impl RttTotals {
+    pub fn add(&mut self, rtt: Duration) {
+        let rtt = rtt.as_micros();
+        self.count += 1;
+        self.sum += rtt;
+        self.squares += rtt * rtt;
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        self.max = self.max.max(rtt);
+    }
+}
+
+/// The statistics iputils ping prints when it is done.
+pub fn print_ping_statistics(
+    host: &str,
+    transmitted: usize,
+    received: usize,
+    errors: usize,
+    elapsed: Duration,
+    rtts: &RttTotals,
+) {
+    println!();
+    print!(
+        "{}",
+        ping_statistics(host, transmitted, received, errors, elapsed, rtts)
+    );
+}
+
+/// The lines of `print_ping_statistics`, after the empty one.
+fn ping_statistics(
+    host: &str,
+    transmitted: usize,
+    received: usize,
+    errors: usize,
+    elapsed: Duration,
+    rtts: &RttTotals,
+) -> String {
+    let mut lines = format!("--- {host} ping statistics ---\n");
+    let loss = match transmitted {
+        0 => 0.0,
+        n => (n - received) as f64 * 100.0 / n as f64,
+    };
+    let errors = match errors {
+        0 => String::new(),
+        n => format!(", +{n} errors"),
+    };
+    lines += &format!(
+        "{transmitted} packets transmitted, {received} received{errors}, {}% packet loss, time {}ms\n",
+        format_g(loss),
+        elapsed.as_millis()
+    );
+    if let Some(min) = rtts.min {
+        // integer arithmetic like iputils, so the averages truncate the same way
+        let avg = rtts.sum / rtts.count;
+        let squares = rtts.squares / rtts.count;
+        let mdev = (squares.saturating_sub(avg * avg) as f64).sqrt() as u128;
+        lines += &format!(
+            "rtt min/avg/max/mdev = {}/{}/{}/{} ms\n",
+            format_millis(min),
+            format_millis(avg),
+            format_millis(rtts.max),
+            format_millis(mdev)
+        );
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn micros(micros: u64) -> Duration {
+        Duration::from_micros(micros)
+    }
+
+    #[test]
+    fn format_time_precision_boundaries() {
+        assert_eq!(format_time(micros(0)), "0.000");
+        assert_eq!(format_time(micros(999)), "0.999");
+        assert_eq!(format_time(micros(1000)), "1.00");
+        assert_eq!(format_time(micros(1004)), "1.00");
+        assert_eq!(format_time(micros(1005)), "1.01");
+        assert_eq!(format_time(micros(9_994)), "9.99");
+        assert_eq!(format_time(micros(9_995)), "10.0");
+        assert_eq!(format_time(micros(99_949)), "99.9");
+        assert_eq!(format_time(micros(99_950)), "100");
+        assert_eq!(format_time(micros(1_234_499)), "1234");
+        // only whole microseconds count
+        assert_eq!(format_time(Duration::from_nanos(999_999)), "0.999");
+    }
+
+    #[test]
+    fn format_g_like_printf() {
+        assert_eq!(format_g(0.0), "0");
+        assert_eq!(format_g(100.0), "100");
+        assert_eq!(format_g(100.0 / 3.0), "33.3333");
+        assert_eq!(format_g(50.0), "50");
+        assert_eq!(format_g(12.5), "12.5");
+        assert_eq!(format_g(200.0 / 3.0), "66.6667");
+    }
+
+    #[test]
+    fn statistics_truncate_like_iputils() {
+        let mut rtts = RttTotals::default();
+        for rtt in [1000, 1001, 1003] {
+            rtts.add(micros(rtt));
+        }
+        // avg 1001.33 and mdev 25.87 both truncate
+        assert_eq!(
+            ping_statistics("10.0.0.1", 4, 3, 0, Duration::from_millis(3004), &rtts),
+            "--- 10.0.0.1 ping statistics ---\n\
+             4 packets transmitted, 3 received, 25% packet loss, time 3004ms\n\
+             rtt min/avg/max/mdev = 1.000/1.001/1.003/0.025 ms\n"
+        );
+    }
+
+    #[test]
+    fn statistics_without_replies() {
+        assert_eq!(
+            ping_statistics(
+                "host (10.0.0.1)",
+                3,
+                0,
+                2,
+                Duration::from_millis(2002),
+                &Default::default()
+            ),
+            "--- host (10.0.0.1) ping statistics ---\n\
+             3 packets transmitted, 0 received, +2 errors, 100% packet loss, time 2002ms\n"
+        );
+        assert_eq!(
+            ping_statistics("10.0.0.1", 0, 0, 0, Duration::ZERO, &Default::default()),
+            "--- 10.0.0.1 ping statistics ---\n\
+             0 packets transmitted, 0 received, 0% packet loss, time 0ms\n"
+        );
+    }
+}
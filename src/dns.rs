@@ -0,0 +1,521 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::plot_data::{Outcome, Sample};
+use crate::ui;
+use clap::ValueEnum;
+use log::{debug, error, info, warn};
+use rand::Rng;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    sync::{mpsc::Sender, Mutex},
+    time::{interval, timeout, Duration, Instant},
+};
+
+/// Record types which can be queried.
+#[derive(Clone, Copy, ValueEnum, Debug, PartialEq, Eq)]
+pub enum RecordType {
+    A,
+    Aaaa,
+    Cname,
+    Mx,
+    Ns,
+    Ptr,
+    Soa,
+    Txt,
+}
+
+impl RecordType {
+    pub fn code(&self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Ns => 2,
+            RecordType::Cname => 5,
+            RecordType::Soa => 6,
+            RecordType::Ptr => 12,
+            RecordType::Mx => 15,
+            RecordType::Txt => 16,
+            RecordType::Aaaa => 28,
+        }
+    }
+}
+
+pub fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        0 => "NOERROR".to_string(),
+        1 => "FORMERR".to_string(),
+        2 => "SERVFAIL".to_string(),
+        3 => "NXDOMAIN".to_string(),
+        4 => "NOTIMP".to_string(),
+        5 => "REFUSED".to_string(),
+        _ => format!("RCODE{rcode}"),
+    }
+}
+
+/// Build a recursive query for `name`.
+pub fn query(id: u16, name: &str, qtype: RecordType) -> io::Result<Vec<u8>> {
+    let mut message = vec![];
+    message.extend_from_slice(&id.to_be_bytes());
+    // recursion desired
+    message.extend_from_slice(&0x0100u16.to_be_bytes());
+    // one question, no answer, authority or additional records
+    message.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    // the root name has no labels at all
+    let labels = match name {
+        "." => None,
+        name => Some(name.strip_suffix('.').unwrap_or(name).split('.')),
+    };
+    for label in labels.into_iter().flatten() {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid label in {name}"),
+            ));
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&qtype.code().to_be_bytes());
+    // class IN
+    message.extend_from_slice(&1u16.to_be_bytes());
+    Ok(message)
+}
+
+/// The parts of a response we keep track of.
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub id: u16,
+    pub rcode: u8,
+    pub truncated: bool,
+    /// Answer records as `TYPE data`, sorted so they can be compared.
+    pub answers: Vec<String>,
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid response: {what}"),
+    )
+}
+
+fn read_u16(message: &[u8], at: usize) -> io::Result<u16> {
+    message
+        .get(at..at + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| invalid("truncated"))
+}
+
+/// Read a possibly compressed name starting at `at`.
+/// Returns the name and the position right after it.
+fn read_name(message: &[u8], mut at: usize) -> io::Result<(String, usize)> {
+    let mut labels = vec![];
+    let mut end = None;
+    // every pointer has to go backwards, which rules out loops
+    let mut limit = at;
+    loop {
+        let len = *message.get(at).ok_or_else(|| invalid("truncated name"))? as usize;
+        match len {
+            0 => {
+                let end = end.unwrap_or(at + 1);
+                return Ok((labels.join("."), end));
+            }
+            len if len & 0xc0 == 0xc0 => {
+                let pointer = read_u16(message, at)? as usize & 0x3fff;
+                if pointer >= limit {
+                    return Err(invalid("forward compression pointer"));
+                }
+                end.get_or_insert(at + 2);
+                limit = pointer;
+                at = pointer;
+            }
+            len => {
+                let label = message
+                    .get(at + 1..at + 1 + len)
+                    .ok_or_else(|| invalid("truncated label"))?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                at += 1 + len;
+            }
+        }
+    }
+}
+
+fn format_rdata(message: &[u8], rtype: u16, start: usize, rdata: &[u8]) -> io::Result<String> {
+    Ok(match (rtype, rdata.len()) {
+        (1, 4) => format!(
+            "A {}",
+            Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])
+        ),
+        (28, 16) => {
+            let octets: [u8; 16] = rdata.try_into().unwrap();
+            format!("AAAA {}", Ipv6Addr::from(octets))
+        }
+        (2, _) => format!("NS {}", read_name(message, start)?.0),
+        (5, _) => format!("CNAME {}", read_name(message, start)?.0),
+        (12, _) => format!("PTR {}", read_name(message, start)?.0),
+        (15, _) => format!(
+            "MX {} {}",
+            read_u16(message, start)?,
+            read_name(message, start + 2)?.0
+        ),
+        (6, _) => format!("SOA {}", read_name(message, start)?.0),
+        (16, _) => {
+            let mut strings = vec![];
+            let mut at = 0;
+            while at < rdata.len() {
+                let len = rdata[at] as usize;
+                let string = rdata.get(at + 1..at + 1 + len).unwrap_or(&rdata[at + 1..]);
+                strings.push(format!("{:?}", String::from_utf8_lossy(string)));
+                at += 1 + len;
+            }
+            format!("TXT {}", strings.join(" "))
+        }
+        (rtype, _) => format!("TYPE{rtype} {}", rdata.len()),
+    })
+}
+
+pub fn parse_response(message: &[u8]) -> io::Result<Response> {
+    if message.len() < 12 {
+        return Err(invalid("shorter than a header"));
+    }
+    let id = read_u16(message, 0)?;
+    let flags = read_u16(message, 2)?;
+    let questions = read_u16(message, 4)?;
+    let answers = read_u16(message, 6)?;
+
+    let mut at = 12;
+    for _ in 0..questions {
+        at = read_name(message, at)?.1 + 4;
+    }
+    let mut records = vec![];
+    for _ in 0..answers {
+        at = read_name(message, at)?.1;
+        let rtype = read_u16(message, at)?;
+        let length = read_u16(message, at + 8)? as usize;
+        let start = at + 10;
+        let rdata = message
+            .get(start..start + length)
+            .ok_or_else(|| invalid("truncated record"))?;
+        records.push(format_rdata(message, rtype, start, rdata)?);
+        at = start + length;
+    }
+    records.sort();
+    Ok(Response {
+        id,
+        rcode: (flags & 0x000f) as u8,
+        truncated: flags & 0x0200 != 0,
+        answers: records,
+    })
+}
+
+/// Repeatedly queries a resolver and tracks response time, rcodes and answers.
+#[derive(Debug)]
+pub struct DnsPinger {
+    name: String,
+    server: SocketAddr,
+    qtype: RecordType,
+    tcp: bool,
+    count: u16,
+    timeout: Duration,
+    interval: Duration,
+    pub latencies: Mutex<Vec<Option<Duration>>>,
+    pub rcodes: Mutex<BTreeMap<String, usize>>,
+    last_answers: Mutex<Option<Vec<String>>>,
+    pub answer_changes: Mutex<usize>,
+    tx: Sender<Sample>,
+}
+
+impl DnsPinger {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: String,
+        server: SocketAddr,
+        qtype: RecordType,
+        tcp: bool,
+        count: u16,
+        timeout: Duration,
+        interval: Duration,
+        tx: Sender<Sample>,
+    ) -> Self {
+        DnsPinger {
+            name,
+            server,
+            qtype,
+            tcp,
+            count,
+            timeout,
+            interval,
+            latencies: Default::default(),
+            rcodes: Default::default(),
+            last_answers: Default::default(),
+            answer_changes: Default::default(),
+            tx,
+        }
+    }
+    pub async fn start(&'static self) {
+        let mut timer = if self.interval.is_zero() {
+            interval(Duration::from_nanos(1))
+        } else {
+            interval(self.interval)
+        };
+        let mut handles = vec![];
+        for seq in 0..self.count {
+            if ui::wait_resumed().await {
+                timer.reset();
+            }
+            timer.tick().await;
+            self.latencies.lock().await.push(None);
+            handles.push(tokio::spawn(self.probe(seq)));
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    }
+    async fn probe(&'static self, seq: u16) {
+        let id = rand::thread_rng().gen();
+        let start = Instant::now();
+        let outcome = match timeout(self.timeout, self.exchange(id)).await {
+            Ok(Ok(response)) => {
+                let latency = start.elapsed();
+                let rcode = rcode_name(response.rcode);
+                info!(
+                    "Query #{seq} for {} answered by {} with {rcode} in {:?}: {}",
+                    self.name,
+                    self.server,
+                    latency,
+                    response.answers.join(", ")
+                );
+                if response.truncated {
+                    warn!("Response #{seq} is truncated, try --tcp");
+                }
+                *self.rcodes.lock().await.entry(rcode).or_insert(0) += 1;
+                let mut last_answers = self.last_answers.lock().await;
+                if let Some(last) = last_answers.as_ref() {
+                    if *last != response.answers {
+                        warn!(
+                            "Answer changed: {} -> {}",
+                            last.join(", "),
+                            response.answers.join(", ")
+                        );
+                        *self.answer_changes.lock().await += 1;
+                    }
+                }
+                *last_answers = Some(response.answers);
+                Outcome::Reply(latency)
+            }
+            Ok(Err(e)) => {
+                error!("Query #{seq} to {} failed: {}", self.server, e);
+                Outcome::Error
+            }
+            Err(_) => {
+                error!("Timeout for query #{seq}");
+                Outcome::Timeout
+            }
+        };
+        self.latencies.lock().await[seq as usize] = outcome.latency();
+        let sample = Sample::new(start.elapsed(), outcome);
+        let _ = self.tx.send(sample).await;
+    }
+    async fn exchange(&self, id: u16) -> io::Result<Response> {
+        let message = query(id, &self.name, self.qtype)?;
+        debug!("Sending query {id} to {}", self.server);
+        let response = if self.tcp {
+            let mut stream = TcpStream::connect(self.server).await?;
+            stream.write_u16(message.len() as u16).await?;
+            stream.write_all(&message).await?;
+            let len = stream.read_u16().await?;
+            let mut buf = vec![0; len as usize];
+            stream.read_exact(&mut buf).await?;
+            parse_response(&buf)?
+        } else {
+            let local: SocketAddr = if self.server.is_ipv4() {
+                "0.0.0.0:0".parse().unwrap()
+            } else {
+                "[::]:0".parse().unwrap()
+            };
+            let socket = UdpSocket::bind(local).await?;
+            socket.connect(self.server).await?;
+            socket.send(&message).await?;
+            let mut buf = vec![0; 65536];
+            // ignore stray datagrams which don't answer our query
+            loop {
+                let n = socket.recv(&mut buf).await?;
+                match parse_response(&buf[..n]) {
+                    Ok(response) if response.id == id => break response,
+                    Ok(response) => warn!("Ignoring response with id {}", response.id),
+                    Err(e) => warn!("{}", e),
+                }
+            }
+        };
+        if response.id != id {
+            return Err(invalid("id does not match the query"));
+        }
+        Ok(response)
+    }
+}
+
+/// First nameserver in /etc/resolv.conf.
+pub fn system_resolver() -> Option<SocketAddr> {
+    let conf = std::fs::read_to_string("/etc/resolv.conf").ok()?;
+    conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|server| server.trim().parse().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    /// Header with one question and `answers` answers, followed by the question
+    /// for example.com A.
+    fn response_head(id: u16, flags: u16, answers: u16) -> Vec<u8> {
+        let mut message = vec![];
+        message.extend_from_slice(&id.to_be_bytes());
+        message.extend_from_slice(&flags.to_be_bytes());
+        message.extend_from_slice(&[0, 1]);
+        message.extend_from_slice(&answers.to_be_bytes());
+        message.extend_from_slice(&[0, 0, 0, 0]);
+        message.extend_from_slice(b"\x07example\x03com\x00");
+        message.extend_from_slice(&[0, 1, 0, 1]);
+        message
+    }
+
+    /// Answer record whose name points to the question at offset 12.
+    fn answer(rtype: u16, rdata: &[u8]) -> Vec<u8> {
+        let mut record = vec![0xc0, 12];
+        record.extend_from_slice(&rtype.to_be_bytes());
+        record.extend_from_slice(&[0, 1, 0, 0, 0x0e, 0x10]);
+        record.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        record.extend_from_slice(rdata);
+        record
+    }
+
+    #[test]
+    fn query_encodes_labels() {
+        let message = query(0x1234, "example.com", RecordType::Aaaa).unwrap();
+        assert_eq!(&message[..12], &[0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&message[12..25], b"\x07example\x03com\x00");
+        assert_eq!(&message[25..], &[0, 28, 0, 1]);
+        // a trailing dot makes no difference
+        assert_eq!(
+            query(0x1234, "example.com.", RecordType::Aaaa).unwrap(),
+            message
+        );
+    }
+
+    #[test]
+    fn query_encodes_root() {
+        let message = query(1, ".", RecordType::Ns).unwrap();
+        assert_eq!(&message[12..], &[0, 0, 2, 0, 1]);
+    }
+
+    #[test]
+    fn query_rejects_invalid_labels() {
+        assert!(query(1, "", RecordType::A).is_err());
+        assert!(query(1, "example..com", RecordType::A).is_err());
+        assert!(query(1, &format!("{}.com", "a".repeat(64)), RecordType::A).is_err());
+        assert!(query(1, &format!("{}.com", "a".repeat(63)), RecordType::A).is_ok());
+    }
+
+    #[test]
+    fn read_name_follows_pointers() {
+        let mut message = response_head(1, 0x8180, 0);
+        // www + pointer to example.com
+        let at = message.len();
+        message.extend_from_slice(&[3, b'w', b'w', b'w', 0xc0, 12]);
+        assert_eq!(
+            read_name(&message, at).unwrap(),
+            ("www.example.com".to_string(), at + 6)
+        );
+        assert_eq!(
+            read_name(&message, 12).unwrap(),
+            ("example.com".to_string(), 25)
+        );
+    }
+
+    #[test]
+    fn read_name_rejects_forward_and_looping_pointers() {
+        let message = [0xc0, 0, 0xc0, 4, 0];
+        assert!(read_name(&message, 0).is_err());
+        assert!(read_name(&message, 2).is_err());
+    }
+
+    #[test]
+    fn read_name_rejects_truncated_names() {
+        assert!(read_name(b"\x07exam", 0).is_err());
+        assert!(read_name(b"\x07example", 0).is_err());
+        assert!(read_name(&[0xc0], 0).is_err());
+    }
+
+    #[test]
+    fn parse_response_reads_answers() {
+        let mut message = response_head(7, 0x8183, 2);
+        message.extend(answer(28, &Ipv6Addr::LOCALHOST.octets()));
+        message.extend(answer(1, &[192, 0, 2, 1]));
+        let response = parse_response(&message).unwrap();
+        assert_eq!(response.id, 7);
+        assert_eq!(rcode_name(response.rcode), "NXDOMAIN");
+        assert!(!response.truncated);
+        assert_eq!(response.answers, vec!["A 192.0.2.1", "AAAA ::1"]);
+    }
+
+    #[test]
+    fn parse_response_reads_compressed_rdata() {
+        let mut message = response_head(7, 0x8180, 1);
+        message.extend(answer(15, &[0, 10, 4, b'm', b'a', b'i', b'l', 0xc0, 12]));
+        let response = parse_response(&message).unwrap();
+        assert_eq!(response.answers, vec!["MX 10 mail.example.com"]);
+    }
+
+    #[test]
+    fn parse_response_rejects_truncated_messages() {
+        let mut message = response_head(7, 0x8380, 1);
+        message.extend(answer(1, &[192, 0, 2, 1]));
+        assert!(parse_response(&message).is_ok());
+        assert!(parse_response(&message[..11]).is_err());
+        // inside the question, the record header and the rdata
+        for len in [20, 30, 40, message.len() - 1] {
+            assert!(parse_response(&message[..len]).is_err(), "{len} bytes");
+        }
+        assert!(parse_response(&message).unwrap().truncated);
+    }
+
+    #[tokio::test]
+    async fn exchange_with_udp_stub() {
+        let stub = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = stub.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 512];
+            let (n, peer) = stub.recv_from(&mut buf).await.unwrap();
+            let id = read_u16(&buf[..n], 0).unwrap();
+            // a stray response with the wrong id comes first and must be skipped
+            let mut stray = response_head(id.wrapping_add(1), 0x8180, 0);
+            stray.extend(answer(1, &[192, 0, 2, 99]));
+            stub.send_to(&stray, peer).await.unwrap();
+            let mut message = response_head(id, 0x8180, 1);
+            message.extend(answer(1, &[192, 0, 2, 1]));
+            stub.send_to(&message, peer).await.unwrap();
+        });
+        let (tx, _rx) = mpsc::channel(1);
+        let pinger = DnsPinger::new(
+            "example.com".to_string(),
+            server,
+            RecordType::A,
+            false,
+            1,
+            Duration::from_secs(1),
+            Duration::ZERO,
+            tx,
+        );
+        let response = timeout(Duration::from_secs(5), pinger.exchange(42))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.id, 42);
+        assert_eq!(response.answers, vec!["A 192.0.2.1"]);
+    }
+}
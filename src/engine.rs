@@ -0,0 +1,461 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::prelude::AsRawFd;
+
+use log::{debug, error, trace};
+use nix::sys::socket::{setsockopt, sockopt::Ipv4RecvErr};
+use pnet_packet::icmp::{IcmpPacket, IcmpTypes};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::{
+    io::{unix::AsyncFd, Interest},
+    select,
+    sync::{mpsc::Sender, Mutex},
+    time::{interval, Duration, Instant, MissedTickBehavior},
+};
+
+use crate::icmp::{self, ProbeKind};
+use crate::pinger::{recv_or_error, sleep_until_some, Received};
+use crate::plot_data::Outcome;
+use crate::ui;
+
+/// What happened to the probes of one of the targets of an `Engine`.
+#[derive(Debug, Clone, Copy)]
+pub enum Event {
+    /// Probe number `index` to `target` was answered, timed out or failed.
+    Probe {
+        target: usize,
+        index: u16,
+        outcome: Outcome,
+    },
+    /// `target` gets no more probes and all its probes are finished.
+    Done(usize),
+}
+
+/// A probe waiting for its reply.
+#[derive(Debug)]
+struct Outstanding {
+    /// Tells the probe apart from a later one which got the same sequence number.
+    id: u64,
+    target: usize,
+    index: u16,
+    sent: Instant,
+}
+
+#[derive(Debug, Default, Clone)]
+struct TargetState {
+    sent: u16,
+    pending: u16,
+    /// Answered while pinging just `once`, so no more probes are sent.
+    answered: bool,
+    done: bool,
+}
+
+/// What the `Engine` knows about its probes, apart from the socket.
+#[derive(Debug, Default)]
+struct Probes {
+    /// Probes waiting for a reply, by target address and sequence number.
+    outstanding: HashMap<(Ipv4Addr, u16), Outstanding>,
+    /// When the outstanding probes time out. All share the timeout,
+    /// so the ones sent first are the ones to time out first.
+    deadlines: VecDeque<(Instant, (Ipv4Addr, u16), u64)>,
+    targets: Vec<TargetState>,
+    count: u16,
+    once: bool,
+}
+
+impl Probes {
+    fn new(targets: usize, count: u16, once: bool) -> Self {
+        Probes {
+            targets: vec![TargetState::default(); targets],
+            count,
+            once,
+            ..Default::default()
+        }
+    }
+    /// Probe `id` is about to be sent to `target` at `host`, and times out at `deadline`.
+    /// Returns its index among the probes of `target`.
+    fn expect(
+        &mut self,
+        target: usize,
+        host: Ipv4Addr,
+        id: u64,
+        sent: Instant,
+        deadline: Instant,
+        events: &mut Vec<Event>,
+    ) -> u16 {
+        let state = &mut self.targets[target];
+        let index = state.sent;
+        state.sent += 1;
+        state.pending += 1;
+        let key = (host, id as u16);
+        let probe = Outstanding {
+            id,
+            target,
+            index,
+            sent,
+        };
+        // the sequence numbers wrapped while it was waiting, it won't be told apart anymore
+        if let Some(old) = self.outstanding.insert(key, probe) {
+            self.finished(old, Outcome::Timeout, events);
+        }
+        self.deadlines.push_back((deadline, key, id));
+        index
+    }
+    /// Finish the outstanding probe `key`, if it is still outstanding and is probe `id`.
+    /// `outcome` gets the time since it was sent.
+    fn finish(
+        &mut self,
+        key: (Ipv4Addr, u16),
+        id: Option<u64>,
+        outcome: impl FnOnce(Duration) -> Outcome,
+        events: &mut Vec<Event>,
+    ) {
+        match self.outstanding.get(&key) {
+            Some(probe) if id.is_none_or(|id| id == probe.id) => {}
+            _ => return,
+        }
+        let probe = self.outstanding.remove(&key).unwrap();
+        let outcome = outcome(probe.sent.elapsed());
+        self.finished(probe, outcome, events);
+    }
+    /// Time out every probe whose deadline is not after `now`.
+    fn expire(&mut self, now: Instant, events: &mut Vec<Event>) {
+        while let Some((at, key, id)) = self.deadlines.front().copied() {
+            if at > now {
+                break;
+            }
+            self.deadlines.pop_front();
+            self.finish(key, Some(id), |_| Outcome::Timeout, events);
+        }
+    }
+    fn finished(&mut self, probe: Outstanding, outcome: Outcome, events: &mut Vec<Event>) {
+        events.push(Event::Probe {
+            target: probe.target,
+            index: probe.index,
+            outcome,
+        });
+        let state = &mut self.targets[probe.target];
+        state.pending -= 1;
+        if self.once && matches!(outcome, Outcome::Reply(_)) {
+            state.answered = true;
+        }
+        if !state.done && state.pending == 0 && (state.answered || state.sent == self.count) {
+            state.done = true;
+            events.push(Event::Done(probe.target));
+        }
+    }
+}
+
+/// Pings many targets from a single socket, unlike `Pinger` which has a socket per target.
+/// Replies are told apart by their source address and sequence number, which
+/// counts through the probes of all targets. A single scheduler sends every probe
+/// and times out the unanswered ones, so memory only grows with the number of
+/// targets and the probes in flight, not with how long it runs.
+#[derive(Debug)]
+pub struct Engine {
+    socket: AsyncFd<Socket>,
+    targets: Vec<Ipv4Addr>,
+    count: u16,
+    size: u16,
+    timeout: Duration,
+    interval: Duration,
+    /// Probes per second to all targets together.
+    rate: u32,
+    probes: Mutex<Probes>,
+    tx: Sender<Event>,
+}
+
+impl Engine {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        targets: Vec<Ipv4Addr>,
+        count: u16,
+        size: u16,
+        timeout: Duration,
+        interval: Duration,
+        rate: u32,
+        once: bool,
+        tx: Sender<Event>,
+    ) -> io::Result<Self> {
+        let sock = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4))?;
+        setsockopt(sock.as_raw_fd(), Ipv4RecvErr, &true)?;
+        sock.set_nonblocking(true)?;
+        let probes = Probes::new(targets.len(), count, once);
+        Ok(Engine {
+            // owned by the socket, so it stays open as long as the AsyncFd
+            socket: unsafe { AsyncFd::register(sock)? },
+            targets,
+            count,
+            size,
+            timeout,
+            interval,
+            rate,
+            probes: Mutex::new(probes),
+            tx,
+        })
+    }
+    pub async fn start(&'static self) {
+        let listen = tokio::spawn(self.listen());
+        self.schedule().await;
+        listen.abort();
+    }
+    /// Send the probes of all targets, `interval` apart for every target and
+    /// at most `rate` per second together, and time out the unanswered ones.
+    async fn schedule(&self) {
+        let now = Instant::now();
+        let mut queue: BinaryHeap<Reverse<(Instant, usize)>> = (0..self.targets.len())
+            .map(|target| Reverse((now, target)))
+            .collect();
+        let mut pace = interval(Duration::from_secs(1) / self.rate.max(1));
+        pace.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut id = 0;
+        loop {
+            let next_deadline = {
+                let probes = self.probes.lock().await;
+                // targets which answered while pinging `once` don't use up the rate
+                while let Some(Reverse((_, target))) = queue.peek() {
+                    match probes.targets[*target].answered {
+                        true => queue.pop(),
+                        false => break,
+                    };
+                }
+                probes.deadlines.front().map(|(at, _, _)| *at)
+            };
+            let next_send = queue.peek().map(|Reverse((at, _))| *at);
+            if next_send.is_none() && next_deadline.is_none() {
+                break;
+            }
+            // waiting for the rate limit and a pause is part of the select,
+            // so probes keep timing out meanwhile
+            let send_due = async {
+                sleep_until_some(next_send).await;
+                ui::wait_resumed().await;
+                pace.tick().await;
+            };
+            select! {
+                _ = send_due => {
+                    let Reverse((at, target)) = queue.pop().unwrap();
+                    // it might have answered while we waited
+                    if self.probes.lock().await.targets[target].answered {
+                        continue;
+                    }
+                    id += 1;
+                    if self.send(target, id).await + 1 < self.count {
+                        queue.push(Reverse((at + self.interval, target)));
+                    }
+                }
+                _ = sleep_until_some(next_deadline) => self.expire().await,
+            }
+        }
+    }
+    /// Send the next probe to `target` and return its index.
+    async fn send(&self, target: usize, id: u64) -> u16 {
+        let host = self.targets[target];
+        let seq = id as u16;
+        let data = ProbeKind::Echo.request(0, seq, self.size);
+        let now = Instant::now();
+        let mut events = vec![];
+        // before sending, the reply might be quicker than us
+        let index =
+            self.probes
+                .lock()
+                .await
+                .expect(target, host, id, now, now + self.timeout, &mut events);
+        self.emit(events).await;
+        let addr: SockAddr = SocketAddr::from((host, 0)).into();
+        match self
+            .socket
+            .async_io(Interest::WRITABLE, |socket| socket.send_to(&data, &addr))
+            .await
+        {
+            Ok(_) => trace!("Sent package {seq} to {host}"),
+            Err(e) => {
+                error!("Failed to send packet to {host}: {}", e);
+                self.finish((host, seq), None, |_| Outcome::Error).await;
+            }
+        }
+        index
+    }
+    /// Time out every probe whose deadline has passed.
+    async fn expire(&self) {
+        let mut events = vec![];
+        self.probes.lock().await.expire(Instant::now(), &mut events);
+        self.emit(events).await;
+    }
+    /// Finish the outstanding probe `key`, see `Probes::finish`.
+    async fn finish(
+        &self,
+        key: (Ipv4Addr, u16),
+        id: Option<u64>,
+        outcome: impl FnOnce(Duration) -> Outcome,
+    ) {
+        let mut events = vec![];
+        self.probes
+            .lock()
+            .await
+            .finish(key, id, outcome, &mut events);
+        self.emit(events).await;
+    }
+    async fn emit(&self, events: Vec<Event>) {
+        for event in events {
+            let _ = self.tx.send(event).await;
+        }
+    }
+    async fn listen(&'static self) {
+        let mut buf = vec![0; 1500];
+        loop {
+            match recv_or_error(&self.socket, &mut buf).await {
+                Ok(Received::Package(n, remote, _)) => {
+                    let is_reply = IcmpPacket::new(&buf[..n])
+                        .is_some_and(|icmp| icmp.get_icmp_type() == IcmpTypes::EchoReply);
+                    if is_reply {
+                        let seq = icmp::sequence_number(&buf[..n]);
+                        self.finish((remote, seq), None, Outcome::Reply).await;
+                    }
+                }
+                Ok(Received::Error(Some(destination), seq, err)) => {
+                    debug!("Probe {seq} to {destination}: {}", err);
+                    self.finish((destination, seq), None, |_| Outcome::Error)
+                        .await;
+                }
+                Ok(Received::Error(None, _, err)) => debug!("{}", err),
+                Err(e) => {
+                    error!("Stopped receiving: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const B: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 2);
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn reply(latency: Duration) -> Outcome {
+        Outcome::Reply(latency)
+    }
+
+    /// The outcomes of `events` by target and index, and which targets are done.
+    fn outcomes(events: &[Event]) -> (Vec<(usize, u16, bool)>, Vec<usize>) {
+        let mut probes = vec![];
+        let mut done = vec![];
+        for event in events {
+            match event {
+                Event::Probe {
+                    target,
+                    index,
+                    outcome,
+                } => probes.push((*target, *index, matches!(outcome, Outcome::Reply(_)))),
+                Event::Done(target) => done.push(*target),
+            }
+        }
+        (probes, done)
+    }
+
+    #[test]
+    fn replies_are_matched_by_address_and_sequence() {
+        let mut probes = Probes::new(2, 1, false);
+        let now = Instant::now();
+        let mut events = vec![];
+        assert_eq!(probes.expect(0, A, 1, now, now + TIMEOUT, &mut events), 0);
+        assert_eq!(probes.expect(1, B, 2, now, now + TIMEOUT, &mut events), 0);
+        assert!(events.is_empty());
+
+        // the sequence number of the other target, or from the wrong address
+        probes.finish((A, 2), None, reply, &mut events);
+        probes.finish((B, 1), None, reply, &mut events);
+        assert!(events.is_empty());
+
+        probes.finish((B, 2), None, reply, &mut events);
+        assert_eq!(outcomes(&events), (vec![(1, 0, true)], vec![1]));
+        // a duplicate reply finds nothing anymore
+        probes.finish((B, 2), None, reply, &mut events);
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn deadlines_time_out_unanswered_probes() {
+        let mut probes = Probes::new(1, 2, false);
+        let now = Instant::now();
+        let mut events = vec![];
+        probes.expect(0, A, 1, now, now + TIMEOUT, &mut events);
+        let later = now + Duration::from_millis(500);
+        assert_eq!(
+            probes.expect(0, A, 2, later, later + TIMEOUT, &mut events),
+            1
+        );
+        probes.finish((A, 2), None, reply, &mut events);
+        assert_eq!(outcomes(&events), (vec![(0, 1, true)], vec![]));
+
+        events.clear();
+        probes.expire(now + TIMEOUT - Duration::from_millis(1), &mut events);
+        assert!(events.is_empty());
+        probes.expire(now + TIMEOUT, &mut events);
+        assert_eq!(outcomes(&events), (vec![(0, 0, false)], vec![0]));
+        // the deadline of the answered probe passes without an event
+        events.clear();
+        probes.expire(later + TIMEOUT, &mut events);
+        assert!(events.is_empty());
+        assert!(probes.deadlines.is_empty());
+    }
+
+    #[test]
+    fn stale_deadline_spares_later_probe_with_same_sequence() {
+        let mut probes = Probes::new(1, u16::MAX, false);
+        let now = Instant::now();
+        let mut events = vec![];
+        probes.expect(0, A, 1, now, now + TIMEOUT, &mut events);
+        probes.finish((A, 1), None, reply, &mut events);
+        // the sequence numbers wrapped, probe 65537 is sent as 1 again
+        let later = now + Duration::from_millis(500);
+        probes.expect(0, A, 65_537, later, later + TIMEOUT, &mut events);
+        events.clear();
+
+        // the deadline of the first probe must not time out the second
+        probes.expire(now + TIMEOUT, &mut events);
+        assert!(events.is_empty());
+        assert!(probes.outstanding.contains_key(&(A, 1)));
+        probes.finish((A, 1), None, reply, &mut events);
+        assert_eq!(outcomes(&events), (vec![(0, 1, true)], vec![]));
+    }
+
+    #[test]
+    fn wrapped_sequence_times_out_the_older_probe() {
+        let mut probes = Probes::new(1, 2, false);
+        let now = Instant::now();
+        let mut events = vec![];
+        probes.expect(0, A, 1, now, now + TIMEOUT, &mut events);
+        probes.expect(0, A, 65_537, now, now + TIMEOUT, &mut events);
+        assert_eq!(outcomes(&events), (vec![(0, 0, false)], vec![]));
+        probes.finish((A, 1), None, reply, &mut events);
+        assert_eq!(
+            outcomes(&events),
+            (vec![(0, 0, false), (0, 1, true)], vec![0])
+        );
+    }
+
+    #[test]
+    fn once_is_done_after_the_first_reply() {
+        let mut probes = Probes::new(1, 3, true);
+        let now = Instant::now();
+        let mut events = vec![];
+        probes.expect(0, A, 1, now, now + TIMEOUT, &mut events);
+        probes.expect(0, A, 2, now, now + TIMEOUT, &mut events);
+        probes.finish((A, 2), None, reply, &mut events);
+        assert!(probes.targets[0].answered);
+        // the first probe is still pending
+        assert_eq!(outcomes(&events), (vec![(0, 1, true)], vec![]));
+        probes.finish((A, 1), None, |_| Outcome::Error, &mut events);
+        assert_eq!(
+            outcomes(&events),
+            (vec![(0, 1, true), (0, 0, false)], vec![0])
+        );
+    }
+}
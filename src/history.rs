@@ -0,0 +1,98 @@
+use crate::plot_data::Outcome;
+use crate::stats::Stats;
+
+/// Width and retention of every tier, in seconds.
+pub const TIERS: [(f64, f64); 3] = [
+    (10.0, 60.0 * 60.0),
+    (60.0, 24.0 * 60.0 * 60.0),
+    (60.0 * 60.0, 30.0 * 24.0 * 60.0 * 60.0),
+];
+
+/// All probes sent within one bucket of a tier.
+#[derive(Debug, Clone)]
+pub struct Bucket {
+    pub start: f64,
+    pub stats: Stats,
+}
+
+/// Probes aggregated into buckets of `width` seconds, kept for `retention` seconds.
+#[derive(Debug, Clone)]
+pub struct Tier {
+    pub width: f64,
+    pub retention: f64,
+    pub buckets: Vec<Bucket>,
+    /// Middle and min, avg and max latency of every bucket with replies,
+    /// so the chart can borrow them.
+    pub lows: Vec<(f64, f64)>,
+    pub points: Vec<(f64, f64)>,
+    pub highs: Vec<(f64, f64)>,
+}
+
+impl Tier {
+    pub fn new(width: f64, retention: f64) -> Self {
+        Tier {
+            width,
+            retention,
+            buckets: vec![],
+            lows: vec![],
+            points: vec![],
+            highs: vec![],
+        }
+    }
+
+    /// Add a probe sent at `x`, which may be older than the last one.
+    pub fn add(&mut self, x: f64, outcome: Outcome) {
+        let start = (x / self.width).floor() * self.width;
+        let at = self.buckets.partition_point(|bucket| bucket.start < start);
+        if self.buckets.get(at).map(|bucket| bucket.start) != Some(start) {
+            let bucket = Bucket {
+                start,
+                stats: Stats::default(),
+            };
+            self.buckets.insert(at, bucket);
+        }
+        let bucket = &mut self.buckets[at];
+        bucket.stats.add(outcome);
+        if let Some(avg) = bucket.stats.mean() {
+            let middle = start + self.width / 2.0;
+            let (min, max) = (bucket.stats.min, bucket.stats.max);
+            let at = self.points.partition_point(|(x, _)| *x < middle);
+            if self.points.get(at).map(|(x, _)| *x) != Some(middle) {
+                self.lows.insert(at, (middle, min));
+                self.points.insert(at, (middle, avg));
+                self.highs.insert(at, (middle, max));
+            }
+            self.lows[at].1 = min;
+            self.points[at].1 = avg;
+            self.highs[at].1 = max;
+        }
+    }
+
+    /// Drop everything older than the retention.
+    pub fn prune(&mut self, now: f64) {
+        let earliest = now - self.retention;
+        let end = self
+            .buckets
+            .partition_point(|bucket| bucket.start + self.width < earliest);
+        self.buckets.drain(..end);
+        let end = self.points.partition_point(|(x, _)| *x < earliest);
+        self.lows.drain(..end);
+        self.points.drain(..end);
+        self.highs.drain(..end);
+    }
+
+    /// Buckets which end after `earliest`.
+    pub fn since(&self, earliest: f64) -> &[Bucket] {
+        let start = self
+            .buckets
+            .partition_point(|bucket| bucket.start + self.width < earliest);
+        &self.buckets[start..]
+    }
+
+    pub fn clear(&mut self) {
+        self.buckets.clear();
+        self.lows.clear();
+        self.points.clear();
+        self.highs.clear();
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use crate::plot_data::{Outcome, Sample};
+use crate::ui;
+use log::{debug, error, info};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::{mpsc::Sender, Mutex},
+    time::{interval, timeout, Duration, Instant},
+};
+use tokio_rustls::{
+    rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
+    TlsConnector,
+};
+use url::{Host, Position, Url};
+
+/// Names of the series sent to the graph, in the order of `HttpTiming::phases`.
+pub const PHASES: [&str; 5] = ["total", "dns", "connect", "tls", "ttfb"];
+
+/// How long each phase of a single request took.
+#[derive(Debug, Clone, Copy)]
+pub struct HttpTiming {
+    pub dns: Duration,
+    pub connect: Duration,
+    /// `None` for plain http.
+    pub tls: Option<Duration>,
+    /// From sending the request to the first byte of the response.
+    pub ttfb: Duration,
+    pub total: Duration,
+    pub status: u16,
+}
+
+impl HttpTiming {
+    pub fn phases(&self) -> Vec<Option<Duration>> {
+        vec![
+            Some(self.total),
+            Some(self.dns),
+            Some(self.connect),
+            self.tls,
+            Some(self.ttfb),
+        ]
+    }
+}
+
+/// Repeatedly requests an URL and splits every request into its phases.
+pub struct HttpPinger {
+    url: Url,
+    count: u16,
+    timeout: Duration,
+    interval: Duration,
+    connector: TlsConnector,
+    pub timings: Mutex<Vec<Option<HttpTiming>>>,
+    /// Total time of every request, for the common summary.
+    pub latencies: Mutex<Vec<Option<Duration>>>,
+    pub statuses: Mutex<BTreeMap<u16, usize>>,
+    tx: Sender<Vec<Option<Sample>>>,
+}
+
+impl std::fmt::Debug for HttpPinger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpPinger")
+            .field("url", &self.url)
+            .field("count", &self.count)
+            .field("timings", &self.timings)
+            .finish()
+    }
+}
+
+impl HttpPinger {
+    pub fn new(
+        url: Url,
+        count: u16,
+        timeout: Duration,
+        interval: Duration,
+        tx: Sender<Vec<Option<Sample>>>,
+    ) -> Self {
+        let mut roots = RootCertStore::empty();
+        roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        HttpPinger {
+            url,
+            count,
+            timeout,
+            interval,
+            connector: TlsConnector::from(Arc::new(config)),
+            timings: Default::default(),
+            latencies: Default::default(),
+            statuses: Default::default(),
+            tx,
+        }
+    }
+    pub async fn start(&'static self) {
+        let mut timer = if self.interval.is_zero() {
+            interval(Duration::from_nanos(1))
+        } else {
+            interval(self.interval)
+        };
+        let mut handles = vec![];
+        for seq in 0..self.count {
+            if ui::wait_resumed().await {
+                timer.reset();
+            }
+            timer.tick().await;
+            self.timings.lock().await.push(None);
+            self.latencies.lock().await.push(None);
+            handles.push(tokio::spawn(self.probe(seq)));
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    }
+    async fn probe(&'static self, seq: u16) {
+        let start = Instant::now();
+        let timing = match timeout(self.timeout, self.request()).await {
+            Ok(Ok(timing)) => {
+                info!(
+                    "Request #{seq} to {}: {} in {:?} (dns {:?}, connect {:?}, tls {:?}, ttfb {:?})",
+                    self.url,
+                    timing.status,
+                    timing.total,
+                    timing.dns,
+                    timing.connect,
+                    timing.tls.unwrap_or_default(),
+                    timing.ttfb
+                );
+                *self.statuses.lock().await.entry(timing.status).or_insert(0) += 1;
+                Ok(timing)
+            }
+            Ok(Err(e)) => {
+                error!("Request #{seq} to {} failed: {}", self.url, e);
+                Err(Outcome::Error)
+            }
+            Err(_) => {
+                error!("Timeout for request #{seq}");
+                Err(Outcome::Timeout)
+            }
+        };
+        self.timings.lock().await[seq as usize] = timing.ok();
+        self.latencies.lock().await[seq as usize] = timing.ok().map(|t| t.total);
+        let elapsed = start.elapsed();
+        // phases which don't apply, like tls for plain http, are left out
+        let phases = match timing {
+            Ok(timing) => timing
+                .phases()
+                .into_iter()
+                .map(|phase| phase.map(|p| Sample::new(elapsed, Outcome::Reply(p))))
+                .collect(),
+            Err(loss) => vec![Some(Sample::new(elapsed, loss)); PHASES.len()],
+        };
+        let _ = self.tx.send(phases).await;
+    }
+    async fn request(&self) -> io::Result<HttpTiming> {
+        let host = self
+            .url
+            .host()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "URL without host"))?;
+        let port = self
+            .url
+            .port_or_known_default()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "URL without port"))?;
+
+        let start = Instant::now();
+        // IPv6 literals are only bracketed in the URL, not in addresses and server names
+        let addr = match host {
+            Host::Domain(name) => tokio::net::lookup_host((name, port))
+                .await?
+                .next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host has no address"))?,
+            Host::Ipv4(ip) => SocketAddr::new(IpAddr::V4(ip), port),
+            Host::Ipv6(ip) => SocketAddr::new(IpAddr::V6(ip), port),
+        };
+        let dns = start.elapsed();
+
+        let stream = TcpStream::connect(addr).await?;
+        let connect = start.elapsed() - dns;
+        debug!("Connected to {addr}");
+
+        let (status, ttfb, tls) = if self.url.scheme() == "https" {
+            let handshake = Instant::now();
+            let server_name = match host {
+                Host::Domain(name) => ServerName::try_from(name)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+                _ => ServerName::IpAddress(addr.ip()),
+            };
+            let stream = self.connector.connect(server_name, stream).await?;
+            let tls = handshake.elapsed();
+            let (status, ttfb) = self.exchange(stream).await?;
+            (status, ttfb, Some(tls))
+        } else {
+            let (status, ttfb) = self.exchange(stream).await?;
+            (status, ttfb, None)
+        };
+        Ok(HttpTiming {
+            dns,
+            connect,
+            tls,
+            ttfb,
+            total: start.elapsed(),
+            status,
+        })
+    }
+    /// Send the request and read the whole response.
+    /// Returns the status code and the time to the first byte.
+    async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut stream: S,
+    ) -> io::Result<(u16, Duration)> {
+        let host = match self.url.port() {
+            Some(port) => format!("{}:{}", self.url.host_str().unwrap(), port),
+            None => self.url.host_str().unwrap().to_string(),
+        };
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: rping/{}\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+            &self.url[Position::BeforePath..Position::AfterQuery],
+            host,
+            env!("CARGO_PKG_VERSION")
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let sent = Instant::now();
+
+        let mut buf = vec![0; 16384];
+        let mut head = vec![];
+        let mut ttfb = None;
+        // the status line may be split over several reads
+        while !head.windows(2).any(|w| w == b"\r\n") {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before the status line",
+                ));
+            }
+            ttfb.get_or_insert_with(|| sent.elapsed());
+            head.extend_from_slice(&buf[..n]);
+        }
+        let status = String::from_utf8_lossy(&head)
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid status line"))?;
+        // we asked the server to close the connection after the response
+        loop {
+            match stream.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(_) => {}
+                // some servers close TLS connections without close_notify
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((status, ttfb.unwrap()))
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    net::Ipv4Addr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use pnet_packet::icmp::{
+    checksum, echo_request::MutableEchoRequestPacket, IcmpPacket, IcmpType, IcmpTypes,
+    MutableIcmpPacket,
+};
+
+const DAY_MS: i64 = 24 * 60 * 60 * 1000;
+
+/// Which ICMP query is sent as a probe.
+#[derive(Clone, Copy, ValueEnum, Debug, PartialEq, Eq)]
+pub enum ProbeKind {
+    Echo,
+    /// Timestamp request (type 13), answered with a timestamp reply (type 14)
+    Timestamp,
+    /// Address mask request (type 17), answered with an address mask reply (type 18)
+    #[clap(alias("address-mask"))]
+    Mask,
+    /// Information request (type 15), answered with an information reply (type 16)
+    #[clap(alias("information"))]
+    Info,
+}
+
+impl ProbeKind {
+    /// Linux ping sockets only allow echo requests, everything else needs a raw socket.
+    pub fn needs_raw_socket(&self) -> bool {
+        *self != ProbeKind::Echo
+    }
+
+    pub fn reply_type(&self) -> IcmpType {
+        match self {
+            ProbeKind::Echo => IcmpTypes::EchoReply,
+            ProbeKind::Timestamp => IcmpTypes::TimestampReply,
+            ProbeKind::Mask => IcmpTypes::AddressMaskReply,
+            ProbeKind::Info => IcmpTypes::InformationReply,
+        }
+    }
+
+    /// Build a request with a valid checksum.
+    /// Only echo requests carry a payload, so `size` is ignored for the other kinds.
+    pub fn request(&self, identifier: u16, seq: u16, size: u16) -> Vec<u8> {
+        let mut data = match self {
+            ProbeKind::Echo => {
+                let mut data = vec![0; size as usize];
+                let mut echo_packet = MutableEchoRequestPacket::new(&mut data[..]).unwrap();
+                echo_packet.set_sequence_number(seq);
+                echo_packet.set_icmp_type(IcmpTypes::EchoRequest);
+                data
+            }
+            ProbeKind::Timestamp => {
+                let mut data = vec![0; 20];
+                data[0] = IcmpTypes::Timestamp.0;
+                data[8..12].copy_from_slice(&ms_since_midnight().to_be_bytes());
+                data
+            }
+            ProbeKind::Mask => {
+                let mut data = vec![0; 12];
+                data[0] = IcmpTypes::AddressMaskRequest.0;
+                data
+            }
+            ProbeKind::Info => {
+                let mut data = vec![0; 8];
+                data[0] = IcmpTypes::InformationRequest.0;
+                data
+            }
+        };
+        data[4..6].copy_from_slice(&identifier.to_be_bytes());
+        data[6..8].copy_from_slice(&seq.to_be_bytes());
+        let sum = checksum(&IcmpPacket::new(&data).unwrap());
+        MutableIcmpPacket::new(&mut data).unwrap().set_checksum(sum);
+        data
+    }
+}
+
+/// Identifier of a query message, which sits in the same place for all kinds.
+pub fn identifier(icmp: &[u8]) -> u16 {
+    u16::from_be_bytes([icmp[4], icmp[5]])
+}
+
+/// Sequence number of a query message, which sits in the same place for all kinds.
+pub fn sequence_number(icmp: &[u8]) -> u16 {
+    u16::from_be_bytes([icmp[6], icmp[7]])
+}
+
+/// Milliseconds since midnight UT, the clock used by ICMP timestamps.
+pub fn ms_since_midnight() -> u32 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    (now.as_millis() % DAY_MS as u128) as u32
+}
+
+/// One-way delays estimated from a timestamp reply, in milliseconds.
+#[derive(Debug, Clone, Copy)]
+pub struct TimestampEstimate {
+    pub forward: i64,
+    pub backward: i64,
+    /// How far the remote clock is ahead of ours, assuming symmetric paths.
+    pub offset: f64,
+}
+
+impl TimestampEstimate {
+    /// Parse a timestamp reply received at `received` (milliseconds since midnight).
+    /// Returns `None` for truncated replies and non-standard timestamps,
+    /// which have the high bit set.
+    pub fn from_reply(icmp: &[u8], received: u32) -> Option<Self> {
+        if icmp.len() < 20 {
+            return None;
+        }
+        let field = |i: usize| u32::from_be_bytes([icmp[i], icmp[i + 1], icmp[i + 2], icmp[i + 3]]);
+        let (originate, receive, transmit) = (field(8), field(12), field(16));
+        if [originate, receive, transmit]
+            .iter()
+            .any(|t| t & 0x8000_0000 != 0)
+        {
+            return None;
+        }
+        let forward = ms_diff(receive, originate);
+        let backward = ms_diff(received, transmit);
+        Some(TimestampEstimate {
+            forward,
+            backward,
+            offset: (forward - backward) as f64 / 2.0,
+        })
+    }
+}
+
+/// `a - b` for two timestamps, wrapping around midnight.
+fn ms_diff(a: u32, b: u32) -> i64 {
+    let diff = (a as i64 - b as i64).rem_euclid(DAY_MS);
+    if diff > DAY_MS / 2 {
+        diff - DAY_MS
+    } else {
+        diff
+    }
+}
+
+/// Address mask carried by an address mask reply.
+pub fn address_mask(icmp: &[u8]) -> Option<Ipv4Addr> {
+    icmp.get(8..12)
+        .map(|mask| Ipv4Addr::new(mask[0], mask[1], mask[2], mask[3]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Internet checksum over the whole message, zero when the checksum field is right.
+    fn verify(data: &[u8]) -> u16 {
+        let mut sum: u32 = data
+            .chunks(2)
+            .map(|word| u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32)
+            .sum();
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+
+    /// Timestamp reply to a request originated at `originate`.
+    fn timestamp_reply(originate: u32, receive: u32, transmit: u32) -> Vec<u8> {
+        let mut data = vec![0; 20];
+        data[0] = IcmpTypes::TimestampReply.0;
+        data[8..12].copy_from_slice(&originate.to_be_bytes());
+        data[12..16].copy_from_slice(&receive.to_be_bytes());
+        data[16..20].copy_from_slice(&transmit.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn request_lengths_and_types() {
+        for (kind, len, icmp_type) in [
+            (ProbeKind::Echo, 32, IcmpTypes::EchoRequest),
+            (ProbeKind::Timestamp, 20, IcmpTypes::Timestamp),
+            (ProbeKind::Mask, 12, IcmpTypes::AddressMaskRequest),
+            (ProbeKind::Info, 8, IcmpTypes::InformationRequest),
+        ] {
+            let data = kind.request(0x1234, 0xabcd, 32);
+            assert_eq!(data.len(), len, "{kind:?}");
+            assert_eq!(data[0], icmp_type.0, "{kind:?}");
+            assert_eq!(data[1], 0, "{kind:?}");
+            assert_eq!(identifier(&data), 0x1234, "{kind:?}");
+            assert_eq!(sequence_number(&data), 0xabcd, "{kind:?}");
+            assert_eq!(verify(&data), 0, "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn echo_request_of_odd_size() {
+        let data = ProbeKind::Echo.request(1, 2, 33);
+        assert_eq!(data.len(), 33);
+        assert_eq!(verify(&data), 0);
+    }
+
+    #[test]
+    fn timestamp_request_carries_originate_time() {
+        let before = ms_since_midnight();
+        let data = ProbeKind::Timestamp.request(1, 2, 0);
+        let originate = u32::from_be_bytes(data[8..12].try_into().unwrap());
+        assert!(ms_diff(originate, before) >= 0);
+        assert!(ms_diff(originate, before) < 1000);
+        assert!(data[12..20].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn ms_diff_wraps_around_midnight() {
+        assert_eq!(ms_diff(1500, 1000), 500);
+        assert_eq!(ms_diff(1000, 1500), -500);
+        // a minute after midnight against a minute before it
+        assert_eq!(ms_diff(60_000, DAY_MS as u32 - 60_000), 120_000);
+        assert_eq!(ms_diff(DAY_MS as u32 - 60_000, 60_000), -120_000);
+        assert_eq!(ms_diff(0, DAY_MS as u32 - 1), 1);
+    }
+
+    #[test]
+    fn from_reply_estimates_delays() {
+        // the remote clock is 100 ms ahead, 10 ms there, 5 ms to answer and 20 ms back
+        let reply = timestamp_reply(1_000, 1_110, 1_115);
+        let estimate = TimestampEstimate::from_reply(&reply, 1_035).unwrap();
+        assert_eq!(estimate.forward, 110);
+        assert_eq!(estimate.backward, -80);
+        assert_eq!(estimate.offset, 95.0);
+    }
+
+    #[test]
+    fn from_reply_across_midnight() {
+        let before = DAY_MS as u32 - 5;
+        let reply = timestamp_reply(before, 3, 4);
+        let estimate = TimestampEstimate::from_reply(&reply, 10).unwrap();
+        assert_eq!(estimate.forward, 8);
+        assert_eq!(estimate.backward, 6);
+    }
+
+    #[test]
+    fn from_reply_rejects_non_standard_and_truncated() {
+        let reply = timestamp_reply(1_000, 0x8000_0000 | 1_100, 1_100);
+        assert!(TimestampEstimate::from_reply(&reply, 1_200).is_none());
+        let reply = timestamp_reply(1_000, 1_100, 0x8000_0000);
+        assert!(TimestampEstimate::from_reply(&reply, 1_200).is_none());
+        let reply = timestamp_reply(1_000, 1_100, 1_100);
+        assert!(TimestampEstimate::from_reply(&reply[..19], 1_200).is_none());
+    }
+
+    #[test]
+    fn address_mask_of_reply() {
+        let mut reply = vec![0; 12];
+        reply[0] = IcmpTypes::AddressMaskReply.0;
+        reply[8..12].copy_from_slice(&[255, 255, 240, 0]);
+        assert_eq!(address_mask(&reply), Some(Ipv4Addr::new(255, 255, 240, 0)));
+        assert_eq!(address_mask(&reply[..11]), None);
+    }
+}
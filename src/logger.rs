@@ -0,0 +1,80 @@
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use stderrlog::StdErrLog;
+use tokio::sync::Notify;
+
+use crate::ui;
+
+/// How many records the event pane keeps.
+const CAPACITY: usize = 1000;
+
+/// A log record shown in the event pane.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub time: SystemTime,
+    pub level: Level,
+    pub message: String,
+}
+
+static EVENTS: Mutex<Vec<Event>> = Mutex::new(Vec::new());
+static LOGGED: Notify = Notify::const_new();
+
+/// Logs to stderr, unless the chart is shown, then records go to its event pane.
+struct Logger {
+    stderr: StdErrLog,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.stderr.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if !ui::active() {
+            self.stderr.log(record);
+            return;
+        }
+        let mut events = EVENTS.lock().unwrap();
+        if events.len() >= CAPACITY {
+            events.remove(0);
+        }
+        events.push(Event {
+            time: SystemTime::now(),
+            level: record.level(),
+            message: record.args().to_string(),
+        });
+        LOGGED.notify_one();
+    }
+
+    fn flush(&self) {
+        self.stderr.flush()
+    }
+}
+
+/// Install the logger, `stderr` decides which records are logged.
+pub fn init(stderr: StdErrLog, level: LevelFilter) -> Result<(), SetLoggerError> {
+    log::set_max_level(level);
+    log::set_boxed_logger(Box::new(Logger { stderr }))
+}
+
+/// Wait until a record is added to the event pane.
+pub async fn logged() {
+    LOGGED.notified().await
+}
+
+/// The `count` records before the last `skip` ones, oldest first.
+pub fn recent(count: usize, skip: usize) -> Vec<Event> {
+    let events = EVENTS.lock().unwrap();
+    let end = events.len().saturating_sub(skip);
+    events[end.saturating_sub(count)..end].to_vec()
+}
+
+/// Number of records in the event pane.
+pub fn len() -> usize {
+    EVENTS.lock().unwrap().len()
+}
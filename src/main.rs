@@ -1,25 +1,57 @@
 use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 
+use arp::ArpPinger;
+use compat::Compat;
+use crossterm::tty::IsTty;
+use dns::{DnsPinger, RecordType};
 use dns_lookup::lookup_host;
-use futures::{stream, StreamExt};
-use log::{error, trace};
-use pinger::Pinger;
-use std::io;
+use engine::Engine;
+use futures::{future::join_all, stream, StreamExt};
+use http::{HttpPinger, HttpTiming};
+use icmp::ProbeKind;
+use log::{error, info, trace, warn, LevelFilter};
+use pinger::{Pacing, Pinger};
+use plot_data::{Outcome, Overlays, PlotData, Sample, Update, YAxis};
+use progress::{spawn_progress, spawn_series_progress};
+use stats::Stats;
+use stderrlog::ColorChoice;
+use sweep::{format_report, read_targets, sweep, Report, Summary};
+use tcping::{TcpPinger, TcpState};
+use theme::{Marker, Palette};
+use trace_data::{TraceData, TraceUpdate};
+use twamp::UdpPinger;
 
-use std::net::{Ipv4Addr, SocketAddr};
-use std::time::Duration;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, Instant};
 
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::sleep;
 use tokio::{select, signal};
-use tui::backend::CrosstermBackend;
-use tui::layout::{Constraint, Direction, Layout};
-use tui::style::{Color, Style};
-
-use tui::widgets::{Axis, Block, Borders, Chart};
-use tui::Terminal;
+use ui::{forward_series, forward_trace, spawn_graph, spawn_series_graph, spawn_trace_graph};
+use url::Url;
 
+mod arp;
+mod compat;
+mod dns;
+mod engine;
+mod history;
+mod http;
+mod icmp;
+mod logger;
 mod pinger;
 mod plot_data;
+mod progress;
+mod stats;
+mod sweep;
+mod tcping;
+mod theme;
+mod trace_data;
+mod twamp;
+mod ui;
 
 #[derive(Parser, Debug)]
 #[clap(version)]
@@ -30,6 +62,73 @@ struct Cli {
     quiet: bool,
     #[clap(long, value_enum, default_value = "off")]
     timestamp: Timestamp,
+    #[clap(
+        long,
+        value_enum,
+        global = true,
+        help = "how the graph draws points",
+        default_value = "braille"
+    )]
+    marker: Marker,
+    #[clap(
+        long,
+        global = true,
+        help = "comma separated colors of the graph series, names or #rrggbb"
+    )]
+    colors: Option<Palette>,
+    #[clap(
+        long,
+        global = true,
+        help = "disable colors, also done by setting NO_COLOR",
+        action = ArgAction::SetTrue
+    )]
+    no_color: bool,
+    #[clap(long, global = true, help = "use a logarithmic latency axis in the graph", action = ArgAction::SetTrue)]
+    log_scale: bool,
+    #[clap(
+        long,
+        global = true,
+        help = "lower bound of the latency axis in the graph"
+    )]
+    y_min: Option<humantime::Duration>,
+    #[clap(
+        long,
+        global = true,
+        help = "upper bound of the latency axis in the graph"
+    )]
+    y_max: Option<humantime::Duration>,
+    #[clap(
+        long,
+        global = true,
+        help = "draw a moving average over this many points in the graph"
+    )]
+    average: Option<usize>,
+    #[clap(
+        long,
+        global = true,
+        value_parser = parse_weight,
+        help = "draw an exponentially weighted moving average, giving the newest point this weight"
+    )]
+    ewma: Option<f64>,
+    #[clap(
+        long,
+        global = true,
+        help = "draw rolling p50/p95/p99 over the moving average window, 20 points by default",
+        action = ArgAction::SetTrue
+    )]
+    percentiles: bool,
+    #[clap(
+        long,
+        global = true,
+        help = "draw the latency objective as a line in the graph"
+    )]
+    slo: Option<humantime::Duration>,
+    #[clap(
+        long,
+        global = true,
+        help = "without the graph, print statistics over this interval, SIGQUIT or SIGUSR1 print them so far"
+    )]
+    stats_every: Option<humantime::Duration>,
 
     #[clap(subcommand)]
     command: Commands,
@@ -46,6 +145,27 @@ enum Commands {
         graph: bool,
         #[clap(long, help = "Timeout for each ping", default_value = "5s")]
         timeout: humantime::Duration,
+        #[clap(
+            long,
+            help = "Show hops live while tracing again and again",
+            action = ArgAction::SetTrue,
+            conflicts_with = "graph"
+        )]
+        tui: bool,
+        #[clap(
+            short,
+            long,
+            help = "time between traces with --tui",
+            default_value = "1s"
+        )]
+        interval: humantime::Duration,
+        #[clap(
+            long,
+            value_enum,
+            help = "Print hops exactly like another traceroute",
+            conflicts_with_all = &["graph", "tui"]
+        )]
+        compat: Option<Compat>,
     },
     Ping {
         #[clap(help = "host to ping")]
@@ -61,17 +181,297 @@ enum Commands {
         broadcast: bool,
         #[clap(short, long, help = "time between pings", default_value = "1s")]
         interval: humantime::Duration,
+        #[clap(
+            long,
+            help = "how pings are spread over time: fixed, jitter:<pct> or poisson",
+            default_value = "fixed"
+        )]
+        schedule: Pacing,
         #[clap(short, long, default_value = "128")]
         ttl: u8,
         #[clap(short, long, default_value = "32")]
         size: u16,
+        #[clap(
+            long = "type",
+            value_enum,
+            help = "ICMP query to send, everything but echo needs a raw socket",
+            default_value = "echo"
+        )]
+        kind: ProbeKind,
         #[clap(short='r', long="route", help = "Don't use the system routing table", action = ArgAction::SetTrue)]
         route: bool,
-        #[clap(long, help = "Timeout for each ping", default_value = "5s")]
+        #[clap(
+            short = 'W',
+            long,
+            help = "Time to wait for each reply, after the last ping at most two round trips once there were replies",
+            default_value = "5s"
+        )]
+        timeout: humantime::Duration,
+        #[clap(
+            short = 'w',
+            long,
+            help = "Stop after this amount of time, regardless of how many pings were sent"
+        )]
+        deadline: Option<humantime::Duration>,
+        #[clap(
+            short = 'l',
+            long,
+            help = "number of pings to send without waiting for the interval",
+            default_value = "0"
+        )]
+        preload: u16,
+        #[clap(long, help = "Exit after the first reply", action = ArgAction::SetTrue)]
+        once: bool,
+        #[clap(
+            short = 'f',
+            long,
+            help = "Send as fast as replies come back, at least 100 pings per second",
+            action = ArgAction::SetTrue,
+            conflicts_with = "adaptive"
+        )]
+        flood: bool,
+        #[clap(
+            short = 'A',
+            long,
+            help = "Adapt the interval to the round trip time, at most 5 pings per second",
+            action = ArgAction::SetTrue
+        )]
+        adaptive: bool,
+        #[clap(
+            long,
+            help = "Ping every address the host resolves to",
+            action = ArgAction::SetTrue
+        )]
+        all_addresses: bool,
+        #[clap(
+            long,
+            help = "Resolve the host again at this interval and follow address changes",
+            conflicts_with = "all-addresses"
+        )]
+        re_resolve: Option<humantime::Duration>,
+        #[clap(short, long, help = "Draw latency graph", action = ArgAction::SetTrue)]
+        graph: bool,
+        #[clap(
+            long,
+            value_enum,
+            help = "Print replies and statistics exactly like another ping",
+            conflicts_with_all = &["graph", "flood"]
+        )]
+        compat: Option<Compat>,
+    },
+    /// Check many hosts at once and print a line for each, like fping
+    Sweep {
+        #[clap(
+            help = "hosts, CIDR blocks like 10.0.0.0/24 or ranges like 10.0.0.1-20 to ping, read from stdin if there are none and no --file"
+        )]
+        hosts: Vec<String>,
+        #[clap(
+            short,
+            long,
+            help = "read hosts from this file, one per line, - for stdin"
+        )]
+        file: Option<PathBuf>,
+        #[clap(
+            short,
+            long,
+            help = "send this many pings to every host and print its statistics",
+            conflicts_with = "vcount"
+        )]
+        count: Option<u16>,
+        #[clap(
+            short = 'C',
+            long,
+            help = "like --count, but print the round trip of every ping"
+        )]
+        vcount: Option<u16>,
+        #[clap(
+            short,
+            long,
+            help = "pings to resend to a silent host before it counts as unreachable, without --count",
+            default_value = "3"
+        )]
+        retries: u16,
+        #[clap(
+            short,
+            long,
+            help = "time between pings to the same host",
+            default_value = "1s"
+        )]
+        interval: humantime::Duration,
+        #[clap(
+            short = 'W',
+            long,
+            help = "Time to wait for a reply",
+            default_value = "500ms"
+        )]
+        timeout: humantime::Duration,
+        #[clap(
+            long,
+            help = "pings per second to all hosts together",
+            default_value = "100"
+        )]
+        rate: u32,
+        #[clap(short, long, default_value = "32")]
+        size: u16,
+        #[clap(
+            short,
+            long,
+            help = "only print the hosts which are alive, without --count",
+            action = ArgAction::SetTrue
+        )]
+        alive: bool,
+        #[clap(
+            short,
+            long,
+            help = "print the round trip of live hosts, without --count",
+            action = ArgAction::SetTrue
+        )]
+        elapsed: bool,
+    },
+    /// Send ARP requests to an on-link host, which answers even if it drops ICMP
+    Arp {
+        #[clap(help = "address to ask for")]
+        host: String,
+        #[clap(short = 'I', long, help = "interface the host is attached to")]
+        interface: String,
+        #[clap(
+            short,
+            long,
+            help = "number of requests to send, use -1 for infinite",
+            default_value = "-1"
+        )]
+        count: i16,
+        #[clap(short, long, help = "time between requests", default_value = "1s")]
+        interval: humantime::Duration,
+        #[clap(
+            short = 'W',
+            long,
+            help = "Time to wait for a reply",
+            default_value = "1s"
+        )]
+        timeout: humantime::Duration,
+        #[clap(short, long, help = "Draw latency graph", action = ArgAction::SetTrue)]
+        graph: bool,
+    },
+    /// Measure TCP handshake time
+    Tcping {
+        #[clap(help = "host:port to connect to")]
+        target: String,
+        #[clap(
+            short,
+            long,
+            help = "number of connections to make, use -1 for infinite",
+            default_value = "-1"
+        )]
+        count: i16,
+        #[clap(short, long, help = "time between connections", default_value = "1s")]
+        interval: humantime::Duration,
+        #[clap(
+            short = 'W',
+            long,
+            help = "Time to wait for the handshake before the port counts as filtered",
+            default_value = "5s"
+        )]
+        timeout: humantime::Duration,
+        #[clap(short, long, help = "Draw latency graph", action = ArgAction::SetTrue)]
+        graph: bool,
+    },
+    /// Measure UDP round trips against a TWAMP-light reflector or an echo server
+    Udp {
+        #[clap(
+            help = "host[:port] of the reflector",
+            long_help = "host[:port] of the reflector, the port defaults to 862"
+        )]
+        target: String,
+        #[clap(
+            short,
+            long,
+            help = "number of packages to send, use -1 for infinite",
+            default_value = "-1"
+        )]
+        count: i16,
+        #[clap(short, long, help = "time between packages", default_value = "1s")]
+        interval: humantime::Duration,
+        #[clap(short, long, default_value = "41")]
+        size: u16,
+        #[clap(
+            long,
+            help = "Target is a plain RFC 862 echo server instead of a TWAMP-light reflector",
+            action = ArgAction::SetTrue
+        )]
+        echo: bool,
+        #[clap(
+            short = 'W',
+            long,
+            help = "Time to wait for a reply",
+            default_value = "5s"
+        )]
+        timeout: humantime::Duration,
+        #[clap(short, long, help = "Draw latency graph", action = ArgAction::SetTrue)]
+        graph: bool,
+    },
+    /// Measure HTTP(S) request latency, split into its phases
+    Http {
+        #[clap(help = "URL to request")]
+        url: Url,
+        #[clap(
+            short,
+            long,
+            help = "number of requests to make, use -1 for infinite",
+            default_value = "-1"
+        )]
+        count: i16,
+        #[clap(short, long, help = "time between requests", default_value = "1s")]
+        interval: humantime::Duration,
+        #[clap(
+            short = 'W',
+            long,
+            help = "Time to wait for a whole request",
+            default_value = "10s"
+        )]
+        timeout: humantime::Duration,
+        #[clap(short, long, help = "Draw latency graph", action = ArgAction::SetTrue)]
+        graph: bool,
+    },
+    /// Measure DNS response time of a resolver
+    Dns {
+        #[clap(help = "name to look up")]
+        name: String,
+        #[clap(
+            long,
+            help = "resolver as ip[:port], defaults to the first nameserver in /etc/resolv.conf"
+        )]
+        server: Option<String>,
+        #[clap(short = 't', long = "type", value_enum, default_value = "a")]
+        qtype: RecordType,
+        #[clap(long, help = "Query over TCP instead of UDP", action = ArgAction::SetTrue)]
+        tcp: bool,
+        #[clap(
+            short,
+            long,
+            help = "number of queries to send, use -1 for infinite",
+            default_value = "-1"
+        )]
+        count: i16,
+        #[clap(short, long, help = "time between queries", default_value = "1s")]
+        interval: humantime::Duration,
+        #[clap(
+            short = 'W',
+            long,
+            help = "Time to wait for a response",
+            default_value = "5s"
+        )]
         timeout: humantime::Duration,
         #[clap(short, long, help = "Draw latency graph", action = ArgAction::SetTrue)]
         graph: bool,
     },
+    /// Reflect TWAMP-light test packets sent by `rping udp`
+    Reflect {
+        #[clap(short, long, default_value = "862")]
+        port: u16,
+        #[clap(short, long, help = "address to listen on", default_value = "0.0.0.0")]
+        bind: IpAddr,
+    },
 }
 
 #[derive(Clone, ValueEnum, Debug)]
@@ -99,20 +499,194 @@ impl From<Timestamp> for stderrlog::Timestamp {
     }
 }
 
+/// Resolve `host:port`, falling back to `default_port` if no port is given.
+/// IPv6 addresses need brackets when a port is given, e.g. `[::1]:80`.
+fn parse_target(target: &str, default_port: Option<u16>) -> Option<SocketAddr> {
+    let (host, port) = match target.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+            (host, port.parse().ok()?)
+        }
+        _ => (target, default_port?),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let ip = lookup_host(host).ok()?.into_iter().next()?;
+    Some(SocketAddr::from((ip, port)))
+}
+
+/// Weight of the newest point in an EWMA.
+fn parse_weight(s: &str) -> Result<f64, String> {
+    let weight: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    match weight > 0.0 && weight <= 1.0 {
+        true => Ok(weight),
+        false => Err(format!("{weight} is not in (0, 1]")),
+    }
+}
+
+/// IPv4 addresses `host` resolves to.
+fn resolve_v4(host: &str) -> Vec<Ipv4Addr> {
+    lookup_host(host)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|x| match x {
+            IpAddr::V4(x) => Some(x),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A pinger which only traces the route to `host`.
+fn trace_pinger(
+    host: Ipv4Addr,
+    size: u16,
+    timeout: Duration,
+    graph: bool,
+    compat: Option<Compat>,
+    numeric: bool,
+) -> &'static Pinger {
+    let (tx, _) = mpsc::channel(1);
+    Box::leak(Box::new(
+        Pinger::new(
+            SocketAddr::from((host, 0)).into(),
+            0,
+            false,
+            size,
+            ProbeKind::Echo,
+            128,
+            timeout,
+            Duration::from_secs(1),
+            Pacing::Fixed,
+            None,
+            0,
+            false,
+            false,
+            tx,
+            graph,
+            compat,
+            numeric,
+        )
+        .unwrap(),
+    ))
+}
+
+/// Print the summary of an ICMP pinger, with the results of timestamp and
+/// address mask probes.
+async fn print_ping_summary(pinger: &Pinger, elapsed: Duration) {
+    print_stats(&pinger.stats().await, elapsed);
+    let timestamps = pinger.timestamps.lock().await;
+    if !timestamps.is_empty() {
+        let n = timestamps.len() as f64;
+        let forward = timestamps.iter().map(|t| t.forward as f64).sum::<f64>() / n;
+        let backward = timestamps.iter().map(|t| t.backward as f64).sum::<f64>() / n;
+        let offset = timestamps.iter().map(|t| t.offset).sum::<f64>() / n;
+        println!(
+            "Average one-way delay: forward {forward:.1} ms, backward {backward:.1} ms, remote clock offset {offset:.1} ms"
+        );
+    }
+    if let Some(mask) = *pinger.address_mask.lock().await {
+        println!("Address mask: {mask}");
+    }
+}
+
+/// Run `probe` until it is done or interrupted.
+/// The terminal is restored afterwards, so the summary isn't printed over the chart.
+async fn run<T>(probe: impl Future<Output = T>) -> Duration {
+    let started = Instant::now();
+    select! {
+        _ = signal::ctrl_c() => {},
+        _ = ui::quit_requested() => {},
+        _ = probe => {}
+    }
+    ui::restore();
+    started.elapsed()
+}
+
+/// Print loss and latency statistics of a finished run.
+fn print_summary(latencies: &[Option<Duration>], elapsed: Duration) {
+    let mut stats = Stats::default();
+    for latency in latencies {
+        match latency {
+            Some(latency) => stats.add(Outcome::Reply(*latency)),
+            None => stats.add(Outcome::Timeout),
+        }
+    }
+    print_stats(&stats, elapsed);
+}
+
+/// Print loss and latency statistics of a finished run, from `stats`.
+fn print_stats(stats: &Stats, elapsed: Duration) {
+    let all = stats.sent();
+    let received = stats.replies;
+    let loss = match all {
+        0 => 0.0,
+        all => (all - received) as f64 / all as f64 * 100.0,
+    };
+    println!(
+        "sent {all} packages, received {received} packages, loss rate {loss:.1}%, time {}ms",
+        elapsed.as_millis()
+    );
+    let ms = |micros: Option<f64>| micros.unwrap_or(f64::NAN) / 1000.0;
+    if let Some(average) = stats.mean() {
+        println!("Average latency: {:.2} ms", average / 1000.0);
+        println!(
+            "min/max/stddev {:.2}/{:.2}/{:.2} ms, jitter {:.2} ms",
+            stats.min / 1000.0,
+            stats.max / 1000.0,
+            ms(stats.stddev()),
+            stats.jitter() / 1000.0
+        );
+        println!(
+            "p50/p95/p99 {:.2}/{:.2}/{:.2} ms",
+            ms(stats.quantile(0.5)),
+            ms(stats.quantile(0.95)),
+            ms(stats.quantile(0.99))
+        );
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let args = Cli::parse();
-    let graph = match args.command {
-        Commands::Trace { .. } => false,
-        Commands::Ping { graph, .. } => graph,
+    let verbosity = args.verbosity as usize + 2;
+    let level = match verbosity {
+        _ if args.quiet => LevelFilter::Off,
+        0 => LevelFilter::Error,
+        1 => LevelFilter::Warn,
+        2 => LevelFilter::Info,
+        3 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let no_color = theme::no_color(args.no_color);
+    ui::set_monochrome(no_color);
+    let palette = args.colors.clone().unwrap_or_default();
+    let marker = args.marker.into();
+    let micros = |bound: Option<humantime::Duration>| {
+        bound.map(|bound| Duration::from(bound).as_micros() as f64)
+    };
+    let y_axis = YAxis {
+        log: args.log_scale,
+        min: micros(args.y_min),
+        max: micros(args.y_max),
     };
-    stderrlog::new()
+    let overlays = Overlays {
+        average: args.average,
+        ewma: args.ewma,
+        percentiles: args.percentiles,
+        slo: micros(args.slo),
+    };
+    let stats_every = args.stats_every.map(Duration::from);
+    let mut stderr = stderrlog::new();
+    stderr
         .module(module_path!())
-        .quiet(args.quiet || graph)
-        .verbosity((args.verbosity + 2) as usize)
+        .quiet(args.quiet)
+        .verbosity(verbosity)
         .timestamp(args.timestamp.clone().into())
-        .init()
-        .unwrap();
+        // stderrlog only checks for a terminal when it installs itself
+        .color(match io::stderr().is_tty() && !no_color {
+            true => ColorChoice::Auto,
+            false => ColorChoice::Never,
+        });
+    // while the chart is shown records go to its event pane
+    logger::init(stderr, level).unwrap();
     trace!("args = {args:?}");
 
     match args.command {
@@ -121,38 +695,67 @@ async fn main() {
             size,
             graph,
             timeout,
+            tui,
+            interval,
+            compat,
         } => {
-            let results = stream::iter(hosts.into_iter().map(|h| {
-                lookup_host(&h)
-                    .unwrap()
+            let targets: Vec<(String, Ipv4Addr)> = hosts
+                .into_iter()
+                .map(|h| {
+                    let ip = lookup_host(&h)
+                        .unwrap()
+                        .into_iter()
+                        .find_map(|x| match x {
+                            std::net::IpAddr::V4(x) => Some(x),
+                            _ => None,
+                        })
+                        .unwrap();
+                    (h, ip)
+                })
+                .collect();
+            if tui {
+                let (trace_tx, trace_rx) = mpsc::channel(10);
+                let data = targets
+                    .iter()
+                    .map(|(name, ip)| TraceData::new(format!("{name} ({ip})")))
+                    .collect();
+                let traces: Vec<_> = targets
                     .into_iter()
-                    .find_map(|x| match x {
-                        std::net::IpAddr::V4(x) => Some(x),
-                        _ => None,
+                    .enumerate()
+                    .map(|(target, (_, host))| {
+                        let (tx, rx) = mpsc::channel(10);
+                        forward_trace(rx, target, trace_tx.clone());
+                        let pinger = trace_pinger(host, size, timeout.into(), true, None, true);
+                        async move {
+                            loop {
+                                if let Err(e) = pinger.traceroute(Some(&tx)).await {
+                                    error!("{}", e);
+                                    break;
+                                }
+                                let _ = tx.send(TraceUpdate::Finished).await;
+                                sleep(interval.into()).await;
+                            }
+                        }
                     })
-                    .unwrap()
-            }))
-            .then(|host| async move {
-                let (tx, _) = mpsc::channel(1);
-                let pinger = Box::leak(Box::new(
-                    Pinger::new(
-                        SocketAddr::from((host, 0)).into(),
-                        0,
-                        false,
-                        size,
-                        128,
-                        timeout.into(),
-                        Duration::from_secs(1),
-                        false,
-                        tx,
-                        graph,
-                    )
-                    .unwrap(),
-                ));
-                pinger.traceroute().await.unwrap()
-            })
-            .collect::<Vec<_>>()
-            .await;
+                    .collect();
+                spawn_trace_graph(trace_rx, data);
+                run(join_all(traces)).await;
+                return;
+            }
+            let results = stream::iter(targets)
+                .then(|(name, host)| async move {
+                    if compat == Some(Compat::Iputils) {
+                        println!(
+                            "traceroute to {name} ({host}), 127 hops max, {} byte packets",
+                            size as u32 + 20
+                        );
+                    }
+                    // unlike ping, traceroute looks up the hops even for an address
+                    let pinger = trace_pinger(host, size, timeout.into(), graph, compat, false);
+                    pinger.traceroute(None).await.unwrap()
+                })
+                .collect::<Vec<_>>()
+                .await;
             trace!("{:?}", results);
             if graph {
                 let max_length = results.iter().map(|x| x.len()).max().unwrap_or(0);
@@ -230,153 +833,615 @@ async fn main() {
             count,
             broadcast,
             interval,
+            schedule,
             ttl,
             size,
+            kind,
             route,
             timeout,
+            deadline,
+            preload,
+            once,
+            flood,
+            adaptive,
+            all_addresses,
+            re_resolve,
             graph,
+            compat,
         } => {
-            let (tx, mut rx) = mpsc::channel(10);
-            let host: Vec<Ipv4Addr> = lookup_host(&_host)
-                .unwrap()
-                .into_iter()
-                .filter_map(|x| match x {
-                    std::net::IpAddr::V4(x) => Some(x),
-                    _ => None,
-                })
-                .collect();
+            let host = resolve_v4(&_host);
             if host.is_empty() {
                 error!("{} is not a valid host", _host);
                 return;
             }
 
-            let mut data = plot_data::PlotData::new(
-                _host.to_string(),
-                150.0,
-                Style::default().fg(Color::Gray),
-                false,
+            let pacing = if flood {
+                Pacing::Flood
+            } else if adaptive {
+                Pacing::Adaptive
+            } else {
+                schedule
+            };
+            info!("Using {pacing} schedule");
+            let targets = if all_addresses { host } else { vec![host[0]] };
+            if !graph {
+                for target in &targets {
+                    match kind {
+                        ProbeKind::Echo => println!(
+                            "PING {_host} ({target}) {}({}) bytes of data.",
+                            size.saturating_sub(8),
+                            size as u32 + 20
+                        ),
+                        _ => println!("PING {_host} ({target}) {kind:?} requests."),
+                    }
+                }
+            }
+
+            let (series_tx, series_rx) = mpsc::channel(10);
+            let mut data = vec![];
+            let mut pingers: Vec<&'static Pinger> = vec![];
+            for (series, target) in targets.iter().enumerate() {
+                let (tx, rx) = mpsc::channel(10);
+                data.push(PlotData::new(
+                    match targets.len() {
+                        1 => _host.to_string(),
+                        _ => format!("{_host} ({target})"),
+                    },
+                    60.0,
+                    palette.style(series),
+                    marker,
+                    y_axis,
+                    overlays,
+                    match pacing {
+                        Pacing::Fixed => Duration::ZERO,
+                        _ => Duration::from_millis(100),
+                    },
+                ));
+                forward_series(rx, series, series_tx.clone());
+                pingers.push(Box::leak(Box::new(
+                    Pinger::new(
+                        SocketAddr::from((*target, 0)).into(),
+                        if count >= 0 { count as u16 } else { u16::MAX },
+                        broadcast,
+                        size,
+                        kind,
+                        ttl,
+                        timeout.into(),
+                        interval.into(),
+                        pacing,
+                        deadline.map(Into::into),
+                        preload,
+                        once,
+                        route,
+                        tx,
+                        graph,
+                        compat,
+                        _host.parse::<Ipv4Addr>().is_ok(),
+                    )
+                    .unwrap(),
+                )));
+            }
+            if let Some(every) = re_resolve {
+                let pinger = pingers[0];
+                let series_tx = series_tx.clone();
+                let name = _host.clone();
+                tokio::spawn(async move {
+                    loop {
+                        sleep(every.into()).await;
+                        let lookup = name.clone();
+                        let addresses = tokio::task::spawn_blocking(move || resolve_v4(&lookup))
+                            .await
+                            .unwrap();
+                        let current = pinger.host().await;
+                        if addresses.is_empty() || addresses.contains(&current) {
+                            continue;
+                        }
+                        info!("{name} now resolves to {}, was {current}", addresses[0]);
+                        pinger.set_host(addresses[0]).await;
+                        if graph {
+                            let label = format!("-> {}", addresses[0]);
+                            let _ = series_tx.send((0, Update::Annotation(label))).await;
+                        }
+                    }
+                });
+            }
+            if graph {
+                spawn_series_graph(series_rx, data);
+            } else {
+                let names = data.iter().map(|series| series.display.clone()).collect();
+                spawn_series_progress(series_rx, names, stats_every);
+            }
+            let elapsed = run(join_all(pingers.iter().map(|pinger| pinger.start()))).await;
+            if pacing == Pacing::Flood && !graph {
+                println!();
+            }
+
+            if compat == Some(Compat::Iputils) {
+                for pinger in &pingers {
+                    let stats = pinger.stats().await;
+                    compat::print_ping_statistics(
+                        &_host,
+                        stats.sent() as usize,
+                        stats.replies as usize,
+                        stats.errors as usize,
+                        elapsed,
+                        &*pinger.rtts.lock().await,
+                    );
+                }
+                return;
+            }
+            for pinger in &pingers {
+                trace!("{:?}", pinger);
+                if pingers.len() > 1 {
+                    println!("--- {} ---", pinger.host().await);
+                }
+                print_ping_summary(pinger, elapsed).await;
+            }
+            println!("schedule {pacing}, interval {interval}");
+            if preload > 1 {
+                println!("preloaded {preload} packages");
+            }
+            if let Some(deadline) = deadline {
+                if elapsed >= deadline.into() {
+                    println!("stopped at deadline {deadline}");
+                }
+            }
+        }
+        Commands::Sweep {
+            hosts,
+            file,
+            count,
+            vcount,
+            retries,
+            interval,
+            timeout,
+            rate,
+            size,
+            alive,
+            elapsed,
+        } => {
+            let names = match read_targets(hosts, file.as_deref()) {
+                Ok(names) => names,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            };
+            // like fping, names which don't resolve make the exit status 2
+            let mut unresolved = false;
+            let mut targets: Vec<(String, Option<Ipv4Addr>)> = vec![];
+            for name in names {
+                let host = resolve_v4(&name).first().copied();
+                match host {
+                    None => {
+                        error!("{} is not a valid host", name);
+                        unresolved = true;
+                    }
+                    Some(host) if pinger::is_broadcast(host).unwrap_or(false) => {
+                        warn!("Skipping {name}, it is the broadcast address of an interface");
+                        targets.push((name, None));
+                        continue;
+                    }
+                    Some(_) => {}
+                }
+                targets.push((name, host));
+            }
+            let resolved: Vec<usize> = (0..targets.len())
+                .filter(|target| targets[*target].1.is_some())
+                .collect();
+            if resolved.is_empty() {
+                error!("No host to ping");
+                process::exit(if unresolved { 2 } else { 1 });
+            }
+            let (report, probes) = match (count, vcount) {
+                (Some(count), _) => (Report::Count, count),
+                (_, Some(vcount)) => (Report::Vcount, vcount),
+                _ => (
+                    Report::Alive {
+                        only: alive,
+                        rtt: elapsed,
+                    },
+                    retries + 1,
+                ),
+            };
+            let (tx, rx) = mpsc::channel(100);
+            let engine = match Engine::new(
+                resolved
+                    .iter()
+                    .map(|target| targets[*target].1.unwrap())
+                    .collect(),
+                probes.max(1),
+                size,
+                timeout.into(),
+                interval.into(),
+                rate,
+                matches!(report, Report::Alive { .. }),
+                tx,
+            ) {
+                Ok(engine) => Box::leak(Box::new(engine)),
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            };
+            let summaries = Mutex::new(vec![Summary::default(); targets.len()]);
+            run(sweep(engine, rx, &targets, &resolved, report, &summaries)).await;
+
+            let summaries = summaries.into_inner();
+            if let Report::Alive { only, .. } = report {
+                // interrupted before they were done
+                for target in &resolved {
+                    let (name, summary) = (&targets[*target].0, &summaries[*target]);
+                    if summary.done || (only && summary.first.is_none()) {
+                        continue;
+                    }
+                    println!("{}", format_report(report, name, 0, summary));
+                }
+            } else {
+                let width = targets
+                    .iter()
+                    .map(|(name, _)| name.len())
+                    .max()
+                    .unwrap_or(0);
+                for ((name, _), summary) in targets.iter().zip(&summaries) {
+                    println!("{}", format_report(report, name, width, summary));
+                }
+            }
+            if unresolved {
+                process::exit(2);
+            }
+            if summaries.iter().any(|summary| summary.stats.replies == 0) {
+                process::exit(1);
+            }
+        }
+        Commands::Tcping {
+            target,
+            count,
+            interval,
+            timeout,
+            graph,
+        } => {
+            let (tx, rx) = mpsc::channel(10);
+            let host = match parse_target(&target, None) {
+                Some(host) => host,
+                None => {
+                    error!("{} is not a valid host:port", target);
+                    return;
+                }
+            };
+
+            let data = PlotData::new(
+                target.clone(),
+                60.0,
+                palette.style(0),
+                marker,
+                y_axis,
+                overlays,
+                Duration::ZERO,
+            );
+            let pinger = Box::leak(Box::new(TcpPinger::new(
+                host,
+                if count >= 0 { count as u16 } else { u16::MAX },
+                timeout.into(),
+                interval.into(),
+                tx,
+            )));
+            if graph {
+                spawn_graph(rx, data);
+            } else {
+                spawn_progress(rx, data.display, stats_every);
+            }
+            let elapsed = run(pinger.start()).await;
+
+            trace!("{:?}", pinger);
+            let states = pinger.states.lock().await;
+            // closed ports answered but no handshake completed, they are only counted below
+            let handshakes: Vec<Option<Duration>> = pinger
+                .latencies
+                .lock()
+                .await
+                .iter()
+                .zip(states.iter())
+                .filter(|(_, state)| **state != Some(TcpState::Closed))
+                .map(|(latency, _)| *latency)
+                .collect();
+            if !handshakes.is_empty() {
+                print_summary(&handshakes, elapsed);
+            }
+            let counts = [
+                TcpState::Open,
+                TcpState::Closed,
+                TcpState::Filtered,
+                TcpState::Error,
+            ]
+            .map(|state| {
+                let n = states.iter().filter(|s| **s == Some(state)).count();
+                format!("{n} {state}")
+            });
+            println!("{}", counts.join(", "));
+        }
+        Commands::Arp {
+            host,
+            interface,
+            count,
+            interval,
+            timeout,
+            graph,
+        } => {
+            let (tx, rx) = mpsc::channel(10);
+            let target = match resolve_v4(&host).first() {
+                Some(target) => *target,
+                None => {
+                    error!("{} is not a valid host", host);
+                    return;
+                }
+            };
+
+            let data = PlotData::new(
+                format!("{host} ({interface})"),
+                60.0,
+                palette.style(0),
+                marker,
+                y_axis,
+                overlays,
+                Duration::ZERO,
             );
+            let pinger = match ArpPinger::new(
+                target,
+                &interface,
+                if count >= 0 { count as u16 } else { u16::MAX },
+                timeout.into(),
+                interval.into(),
+                tx,
+            ) {
+                Ok(pinger) => Box::leak(Box::new(pinger)),
+                Err(e) => {
+                    error!("Failed to open {interface}: {}", e);
+                    return;
+                }
+            };
+            if graph {
+                spawn_graph(rx, data);
+            } else {
+                spawn_progress(rx, data.display, stats_every);
+            }
+            let elapsed = run(pinger.start()).await;
 
+            trace!("{:?}", pinger);
+            print_summary(&pinger.latencies.lock().await, elapsed);
+            let macs = pinger.macs.lock().await;
+            let replies: Vec<_> = macs.iter().map(|(mac, n)| format!("{n}x {mac}")).collect();
+            if !replies.is_empty() {
+                println!("replies from: {}", replies.join(", "));
+            }
+            if macs.len() > 1 {
+                println!("{target} is used by {} hosts", macs.len());
+            }
+        }
+        Commands::Udp {
+            target,
+            count,
+            interval,
+            size,
+            echo,
+            timeout,
+            graph,
+        } => {
+            let (tx, rx) = mpsc::channel(10);
+            let host = match parse_target(&target, Some(862)) {
+                Some(host) => host,
+                None => {
+                    error!("{} is not a valid host", target);
+                    return;
+                }
+            };
+
+            let data = PlotData::new(
+                target.clone(),
+                60.0,
+                palette.style(0),
+                marker,
+                y_axis,
+                overlays,
+                Duration::ZERO,
+            );
             let pinger = Box::leak(Box::new(
-                Pinger::new(
-                    SocketAddr::from((host[0], 0)).into(),
+                UdpPinger::new(
+                    host,
                     if count >= 0 { count as u16 } else { u16::MAX },
-                    broadcast,
-                    size,
-                    ttl,
+                    size as usize,
+                    echo,
                     timeout.into(),
                     interval.into(),
-                    route,
                     tx,
-                    graph,
                 )
+                .await
                 .unwrap(),
             ));
-            let stdout = io::stdout();
-            // execute!(stdout, EnterAlternateScreen, EnableMouseCapture).unwrap();
-            let backend = CrosstermBackend::new(stdout);
-            let mut terminal = Terminal::new(backend).unwrap();
             if graph {
-                // enable_raw_mode().unwrap();
+                spawn_graph(rx, data);
+            } else {
+                spawn_progress(rx, data.display, stats_every);
+            }
+            let elapsed = run(pinger.start()).await;
 
-                terminal.clear().unwrap();
-                tokio::spawn(async move {
-                    while let Some(update) = rx.recv().await {
-                        data.update(update);
-                        // update
-                        terminal
-                            .draw(|f| {
-                                // Split our
-                                let chunks = Layout::default()
-                                    .direction(Direction::Vertical)
-                                    .vertical_margin(1)
-                                    .horizontal_margin(0)
-                                    .constraints(
-                                        [Constraint::Length(1), Constraint::Percentage(100)]
-                                            .as_mut_slice(),
-                                    )
-                                    .split(f.size());
-
-                                let header_chunks = chunks[0].to_owned();
-                                let chart_chunk = chunks[1].to_owned();
-
-                                let header_layout = Layout::default()
-                                    .direction(Direction::Horizontal)
-                                    .constraints(
-                                        [
-                                            Constraint::Percentage(28),
-                                            Constraint::Percentage(12),
-                                            Constraint::Percentage(12),
-                                            Constraint::Percentage(12),
-                                            Constraint::Percentage(12),
-                                            Constraint::Percentage(12),
-                                            Constraint::Percentage(12),
-                                        ]
-                                        .as_ref(),
-                                    )
-                                    .split(header_chunks);
-
-                                for (area, paragraph) in
-                                    header_layout.into_iter().zip(data.header_stats())
-                                {
-                                    f.render_widget(paragraph, area);
-                                }
+            trace!("{:?}", pinger);
+            print_summary(&pinger.latencies.lock().await, elapsed);
+            if !echo {
+                let (forward_lost, backward_lost) = pinger.loss_by_direction().await;
+                println!("lost {forward_lost} packages forward, {backward_lost} backward");
+                let one_way = pinger.one_way.lock().await;
+                if !one_way.is_empty() {
+                    let n = one_way.len() as f64;
+                    let forward = one_way.iter().map(|d| d.forward).sum::<f64>() / n;
+                    let backward = one_way.iter().map(|d| d.backward).sum::<f64>() / n;
+                    println!(
+                        "Average one-way delay: forward {forward:.2} ms, backward {backward:.2} ms (needs synchronized clocks)"
+                    );
+                }
+            }
+        }
+        Commands::Http {
+            url,
+            count,
+            interval,
+            timeout,
+            graph,
+        } => {
+            if !matches!(url.scheme(), "http" | "https") {
+                error!("Only http and https URLs are supported");
+                return;
+            }
+            let (tx, rx) = mpsc::channel(10);
+            let data = http::PHASES
+                .iter()
+                .enumerate()
+                .map(|(series, phase)| {
+                    PlotData::new(
+                        format!("{url} {phase}"),
+                        60.0,
+                        palette.style(series),
+                        marker,
+                        y_axis,
+                        overlays,
+                        Duration::ZERO,
+                    )
+                })
+                .collect();
+            let url_name = url.to_string();
+            let pinger = Box::leak(Box::new(HttpPinger::new(
+                url,
+                if count >= 0 { count as u16 } else { u16::MAX },
+                timeout.into(),
+                interval.into(),
+                tx,
+            )));
+            let (series_tx, series_rx) = mpsc::channel(10);
+            let mut rx: mpsc::Receiver<Vec<Option<Sample>>> = rx;
+            // the progress lines only cover the whole request
+            let phases = if graph { http::PHASES.len() } else { 1 };
+            tokio::spawn(async move {
+                while let Some(samples) = rx.recv().await {
+                    for (series, phase) in samples.into_iter().take(phases).enumerate() {
+                        let phase = match phase {
+                            Some(phase) => phase,
+                            None => continue,
+                        };
+                        if series_tx
+                            .send((series, Update::Sample(phase)))
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                }
+            });
+            if graph {
+                spawn_series_graph(series_rx, data);
+            } else {
+                spawn_series_progress(series_rx, vec![url_name], stats_every);
+            }
+            let elapsed = run(pinger.start()).await;
 
-                                let datasets = vec![(&data).dataset()];
-
-                                let y_axis_bounds = data.y_axis_bounds();
-                                let x_axis_bounds = data.x_axis_bounds();
-
-                                let chart = Chart::new(datasets)
-                                    .block(Block::default().borders(Borders::NONE))
-                                    .x_axis(
-                                        Axis::default()
-                                            .style(Style::default().fg(Color::Gray))
-                                            .bounds(x_axis_bounds)
-                                            .labels(data.x_axis_labels(x_axis_bounds)),
-                                    )
-                                    .y_axis(
-                                        Axis::default()
-                                            .style(Style::default().fg(Color::Gray))
-                                            .bounds(y_axis_bounds)
-                                            .labels(data.y_axis_labels(y_axis_bounds)),
-                                    );
-
-                                f.render_widget(chart, chart_chunk);
-                            })
-                            .unwrap();
+            trace!("{:?}", pinger);
+            print_summary(&pinger.latencies.lock().await, elapsed);
+            let timings = pinger.timings.lock().await;
+            let timings: Vec<_> = timings.iter().flatten().collect();
+            if !timings.is_empty() {
+                let average = |phase: fn(&HttpTiming) -> Option<Duration>| {
+                    let phases: Vec<Duration> = timings.iter().filter_map(|t| phase(t)).collect();
+                    match phases.len() {
+                        0 => "-".to_string(),
+                        n => format!("{:?}", phases.iter().sum::<Duration>() / n as u32),
                     }
-                });
+                };
+                println!(
+                    "Average dns {}, connect {}, tls {}, ttfb {}",
+                    average(|t| Some(t.dns)),
+                    average(|t| Some(t.connect)),
+                    average(|t| t.tls),
+                    average(|t| Some(t.ttfb))
+                );
             }
-            select! {
-                _ = signal::ctrl_c() => {},
-                _ =  pinger.start() => {}
+            let statuses = pinger.statuses.lock().await;
+            let statuses: Vec<_> = statuses
+                .iter()
+                .map(|(status, n)| format!("{n}x {status}"))
+                .collect();
+            println!("status codes: {}", statuses.join(", "));
+        }
+        Commands::Dns {
+            name,
+            server,
+            qtype,
+            tcp,
+            count,
+            interval,
+            timeout,
+            graph,
+        } => {
+            let (tx, rx) = mpsc::channel(10);
+            let server = match server {
+                Some(server) => parse_target(&server, Some(53)),
+                None => dns::system_resolver(),
+            };
+            let server = match server {
+                Some(server) => server,
+                None => {
+                    error!("No valid resolver to query");
+                    return;
+                }
+            };
+
+            let data = PlotData::new(
+                format!("{name} @{server}"),
+                60.0,
+                palette.style(0),
+                marker,
+                y_axis,
+                overlays,
+                Duration::ZERO,
+            );
+            let pinger = Box::leak(Box::new(DnsPinger::new(
+                name,
+                server,
+                qtype,
+                tcp,
+                if count >= 0 { count as u16 } else { u16::MAX },
+                timeout.into(),
+                interval.into(),
+                tx,
+            )));
+            if graph {
+                spawn_graph(rx, data);
+            } else {
+                spawn_progress(rx, data.display, stats_every);
             }
+            let elapsed = run(pinger.start()).await;
 
             trace!("{:?}", pinger);
-            let received = pinger
-                .latencies
-                .lock()
-                .await
+            print_summary(&pinger.latencies.lock().await, elapsed);
+            let rcodes = pinger.rcodes.lock().await;
+            let rcodes: Vec<_> = rcodes
                 .iter()
-                .filter(|x| x.is_some())
-                .count();
-            let all = pinger.latencies.lock().await.len();
+                .map(|(rcode, n)| format!("{n}x {rcode}"))
+                .collect();
+            println!("rcodes: {}", rcodes.join(", "));
             println!(
-                "sent {all} packages, received {received} packages, loss rate {:.1}%",
-                (all - received) as f64 / all as f64 * 100.0
+                "answer changed {} times",
+                pinger.answer_changes.lock().await
             );
-            let average = pinger
-                .latencies
-                .lock()
-                .await
-                .iter()
-                .flatten()
-                .sum::<std::time::Duration>()
-                .as_micros() as f64
-                / received as f64
-                / 1000.0;
-            println!("Average latency: {average:.2} ms");
+        }
+        Commands::Reflect { port, bind } => {
+            select! {
+                _ = signal::ctrl_c() => {},
+                result = twamp::reflect(SocketAddr::from((bind, port))) => {
+                    if let Err(e) = result {
+                        error!("{}", e);
+                    }
+                }
+            }
         }
     }
 }
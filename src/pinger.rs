@@ -1,39 +1,43 @@
 use std::{
-    io::{self, IoSliceMut},
-    mem::MaybeUninit,
-    net::Ipv4Addr,
-    ops::Index,
+    collections::{HashMap, VecDeque},
+    fmt::{self, Display},
+    io::{self, IoSliceMut, Write},
+    mem,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     os::unix::prelude::AsRawFd,
-    process::exit,
+    process::{self, exit},
+    str::FromStr,
 };
 
-use async_io::Async;
-
+use crate::compat::{self, Compat};
+use crate::icmp::{self, ProbeKind, TimestampEstimate};
+use crate::plot_data::{Outcome, Sample};
+use crate::stats::Stats;
+use crate::trace_data::TraceUpdate;
+use crate::ui;
+use futures::future::pending;
 use log::{debug, error, info, trace, warn};
 use nix::{
     ifaddrs::getifaddrs,
-    libc::{sock_extended_err, SO_EE_ORIGIN_ICMP},
+    libc::{self, sock_extended_err, SO_EE_ORIGIN_ICMP},
     sys::socket::{
         recvmsg, setsockopt, sockopt::DontRoute, sockopt::Ipv4RecvErr, MsgFlags, SockaddrIn,
         SockaddrStorage,
     },
 };
 use pnet_packet::{
-    icmp::{
-        echo_reply::EchoReplyPacket,
-        echo_request::{EchoRequestPacket, MutableEchoRequestPacket},
-        IcmpPacket, IcmpTypes, MutableIcmpPacket,
-    },
+    icmp::{echo_request::EchoRequestPacket, IcmpPacket, IcmpTypes, MutableIcmpPacket},
     Packet, PacketSize,
 };
 use quick_error::quick_error;
+use rand::Rng;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use tokio::sync::{mpsc::Sender, Notify};
 use tokio::{
+    io::{unix::AsyncFd, Interest, Ready},
     select,
     sync::{Mutex, RwLock},
-    task::JoinHandle,
-    time::{interval, sleep, Duration, Instant},
+    time::{interval_at, sleep, sleep_until, Duration, Instant, Interval},
 };
 
 // HostUnreachable(Ipv4Addr, u16)
@@ -44,7 +48,7 @@ use tokio::{
 // Unknown(Ipv4Addr, u16, u8, u8)
 quick_error! {
     #[derive(Debug)]
-    enum IcmpError {
+    pub enum IcmpError {
         NetworkUnreachable(ip: Ipv4Addr, seq: u16) {
             display("Network unreachable from {}, seq: {}", ip, seq)
         }
@@ -77,6 +81,47 @@ quick_error! {
     }
 }
 
+impl IcmpError {
+    /// Sender, sequence number and description like iputils prints them.
+    fn iputils(&self) -> Option<(Ipv4Addr, u16, String)> {
+        let (ip, seq, message) = match self {
+            IcmpError::NetworkUnreachable(ip, seq) => {
+                (ip, seq, "Destination Net Unreachable".to_string())
+            }
+            IcmpError::HostUnreachable(ip, seq) => {
+                (ip, seq, "Destination Host Unreachable".to_string())
+            }
+            IcmpError::ProtocolUnreachable(ip, seq) => {
+                (ip, seq, "Destination Protocol Unreachable".to_string())
+            }
+            IcmpError::PortUnreachable(ip, seq) => {
+                (ip, seq, "Destination Port Unreachable".to_string())
+            }
+            IcmpError::OtherUnreachable(ip, seq, code) => {
+                (ip, seq, format!("Dest Unreachable, Bad Code: {code}"))
+            }
+            IcmpError::TimeExceeded(ip, seq) => (ip, seq, "Time to live exceeded".to_string()),
+            IcmpError::Unknown(ip, seq, ee_type, _)
+            | IcmpError::UnknownOrigin(ip, seq, _, _, ee_type) => {
+                (ip, seq, format!("Bad ICMP type: {ee_type}"))
+            }
+            IcmpError::Io(_) => return None,
+        };
+        Some((*ip, *seq, message))
+    }
+    /// Sender and the annotation traceroute puts after the time of an unreachable hop.
+    fn traceroute_flag(&self) -> Option<(Ipv4Addr, &'static str)> {
+        match self {
+            IcmpError::NetworkUnreachable(ip, _) => Some((*ip, "!N")),
+            IcmpError::HostUnreachable(ip, _) => Some((*ip, "!H")),
+            IcmpError::ProtocolUnreachable(ip, _) => Some((*ip, "!P")),
+            IcmpError::PortUnreachable(ip, _) => Some((*ip, "")),
+            IcmpError::OtherUnreachable(ip, _, _) => Some((*ip, "!X")),
+            _ => None,
+        }
+    }
+}
+
 impl From<(sock_extended_err, Ipv4Addr, u16)> for IcmpError {
     fn from((err, addr, seq): (sock_extended_err, Ipv4Addr, u16)) -> Self {
         match err.ee_origin {
@@ -96,21 +141,120 @@ impl From<(sock_extended_err, Ipv4Addr, u16)> for IcmpError {
     }
 }
 
+/// Interval used by flood mode when no reply arrives in time, i.e. at least
+/// 100 packages per second.
+const FLOOD_INTERVAL: Duration = Duration::from_millis(10);
+/// Adaptive mode never sends faster than this, like iputils for normal users.
+const ADAPTIVE_MIN_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How packages after the preload are paced.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pacing {
+    /// One package every interval.
+    Fixed,
+    /// Interval randomly stretched or shrunk by up to the given percentage.
+    Jitter(f64),
+    /// Exponentially distributed gaps with the interval as mean (RFC 2330).
+    Poisson,
+    /// Send as soon as a reply arrives, or every `FLOOD_INTERVAL`.
+    Flood,
+    /// Send as soon as a reply arrives (or the reply wait expires),
+    /// but not faster than `ADAPTIVE_MIN_INTERVAL`.
+    Adaptive,
+}
+
+impl Display for Pacing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pacing::Fixed => write!(f, "fixed"),
+            Pacing::Jitter(pct) => write!(f, "jitter:{pct}"),
+            Pacing::Poisson => write!(f, "poisson"),
+            Pacing::Flood => write!(f, "flood"),
+            Pacing::Adaptive => write!(f, "adaptive"),
+        }
+    }
+}
+
+impl FromStr for Pacing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None => match s {
+                "fixed" => Ok(Pacing::Fixed),
+                "poisson" => Ok(Pacing::Poisson),
+                "flood" => Ok(Pacing::Flood),
+                "adaptive" => Ok(Pacing::Adaptive),
+                _ => Err(format!("unknown schedule {s}")),
+            },
+            Some(("jitter", pct)) => {
+                let pct: f64 = pct
+                    .trim_end_matches('%')
+                    .parse()
+                    .map_err(|e| format!("invalid jitter percentage {pct}: {e}"))?;
+                if !(0.0..=100.0).contains(&pct) {
+                    return Err(format!("jitter percentage {pct} is not in 0..=100"));
+                }
+                Ok(Pacing::Jitter(pct))
+            }
+            Some(_) => Err(format!("unknown schedule {s}")),
+        }
+    }
+}
+
+/// A probe of a `Pinger` waiting for its reply.
+#[derive(Debug)]
+struct Outstanding {
+    sent: Instant,
+    /// With `broadcast` answered probes stay until they time out,
+    /// so the replies of every host are shown.
+    answered: bool,
+}
+
+/// The probes of a `Pinger` which are not finished yet. Like with `Engine`, a
+/// single task times them out, so memory doesn't grow with how long it runs.
+#[derive(Debug, Default)]
+struct Probes {
+    sent: u16,
+    outstanding: HashMap<u16, Outstanding>,
+    /// When the outstanding probes time out, the earliest first.
+    deadlines: VecDeque<(Instant, u16)>,
+}
+
+/// Pings a single target. Unlike with `Engine` every `Pinger` has a socket of
+/// its own, since the TTL, broadcast, `--route` and the raw sockets of the other
+/// query kinds are options of the socket, and `--re-resolve` moves the target.
 #[derive(Debug)]
 pub struct Pinger {
-    socket: Async<Socket>,
-    starts: RwLock<Vec<Instant>>,
-    host: SockAddr,
+    socket: AsyncFd<Socket>,
+    host: RwLock<SockAddr>,
+    broadcast: bool,
     count: u16,
     size: u16,
+    kind: ProbeKind,
+    identifier: u16,
     timeout: Duration,
     interval: Duration,
-    timeout_handles: Mutex<Vec<JoinHandle<()>>>,
-    pub latencies: Mutex<Vec<Option<Duration>>>,
+    pacing: Pacing,
+    deadline: Option<Duration>,
+    preload: u16,
+    once: bool,
+    probes: Mutex<Probes>,
+    stats: Mutex<Stats>,
+    /// Round trips of the answered probes, for the iputils statistics.
+    pub rtts: Mutex<compat::RttTotals>,
+    pub timestamps: Mutex<Vec<TimestampEstimate>>,
+    pub address_mask: Mutex<Option<Ipv4Addr>>,
     finished: Notify,
-    tx: Sender<Option<Duration>>,
-    latencies_sent: Mutex<u16>,
+    /// A probe got a deadline, which might be the earliest.
+    scheduled: Notify,
+    replied: Notify,
+    tx: Sender<Sample>,
     graph: bool,
+    compat: Option<Compat>,
+    /// Print addresses without looking up their names.
+    numeric: bool,
+    names: Mutex<HashMap<Ipv4Addr, Option<String>>>,
 }
 
 impl Pinger {
@@ -120,114 +264,312 @@ impl Pinger {
         count: u16,
         broadcast: bool,
         size: u16,
+        kind: ProbeKind,
         ttl: u8,
         timeout: Duration,
         interval: Duration,
+        pacing: Pacing,
+        deadline: Option<Duration>,
+        preload: u16,
+        once: bool,
         route: bool,
-        tx: Sender<Option<Duration>>,
+        tx: Sender<Sample>,
         graph: bool,
+        compat: Option<Compat>,
+        numeric: bool,
     ) -> io::Result<Self> {
-        let addrs = getifaddrs()?;
-        for ifaddr in addrs {
-            match ifaddr.broadcast {
-                Some(addr) => {
-                    if addr.as_sockaddr_in().is_some() {
-                        let braddr = addr.as_sockaddr_in().unwrap();
-                        let host: SockaddrIn = host.as_socket_ipv4().unwrap().into();
-                        if *braddr == host && !broadcast {
-                            error!("You should specify broadcast option");
-                            exit(-1);
-                        }
-                    }
-                }
-                None => {}
-            }
+        if !broadcast && is_broadcast(*host.as_socket_ipv4().unwrap().ip())? {
+            error!("You should specify broadcast option");
+            exit(-1);
         }
-        let sock = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4)).unwrap();
+        let sock = if kind.needs_raw_socket() {
+            Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))?
+        } else {
+            Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4)).unwrap()
+        };
         sock.set_broadcast(broadcast)?;
         sock.set_ttl(ttl as u32)?;
         setsockopt(sock.as_raw_fd(), Ipv4RecvErr, &true)?;
+        set_recv_ttl(&sock)?;
         if route {
             setsockopt(sock.as_raw_fd(), DontRoute, &true)?;
         }
+        sock.set_nonblocking(true)?;
         Ok(Pinger {
-            socket: Async::new(sock)?,
-            host,
+            // the socket owns its descriptor, which stays open until the AsyncFd is dropped
+            socket: unsafe { AsyncFd::register(sock)? },
+            host: RwLock::new(host),
+            broadcast,
             count,
             size,
+            kind,
+            // ping sockets choose the identifier themselves, raw sockets don't
+            identifier: process::id() as u16,
             timeout,
             interval,
-            starts: Default::default(),
-            timeout_handles: Default::default(),
-            latencies: Default::default(),
+            pacing,
+            deadline,
+            preload,
+            once,
+            probes: Default::default(),
+            stats: Default::default(),
+            rtts: Default::default(),
+            timestamps: Default::default(),
+            address_mask: Default::default(),
             finished: Default::default(),
+            scheduled: Default::default(),
+            replied: Default::default(),
             tx,
-            latencies_sent: Default::default(),
             graph,
+            compat,
+            numeric,
+            names: Default::default(),
         })
     }
+    pub async fn host(&self) -> Ipv4Addr {
+        *self.host.read().await.as_socket_ipv4().unwrap().ip()
+    }
+    /// Send all following packages to `host`, e.g. after the DNS record changed.
+    pub async fn set_host(&self, host: Ipv4Addr) {
+        *self.host.write().await = SocketAddr::from((host, 0)).into();
+    }
+    /// Statistics of the finished probes, the ones still waiting for a reply count as lost.
+    pub async fn stats(&self) -> Stats {
+        let mut stats = self.stats.lock().await.clone();
+        let probes = self.probes.lock().await;
+        for probe in probes.outstanding.values().filter(|probe| !probe.answered) {
+            trace!("Probe sent {:?} ago is unfinished", probe.sent.elapsed());
+            stats.add(Outcome::Timeout);
+        }
+        stats
+    }
     pub async fn start(&'static self) {
-        let b = tokio::spawn(self.listen());
-        let a = tokio::spawn(self.ping(b));
-        a.await.unwrap();
-        self.finished.notified().await;
-    }
-    async fn ping(&'static self, listen_handle: JoinHandle<()>) {
-        let listen_handle = Box::leak(Box::new(listen_handle));
-        if self.starts.read().await.len() != 0 {
+        let listen = tokio::spawn(self.listen());
+        let expire = tokio::spawn(self.expire());
+        let ping = tokio::spawn(self.ping());
+        let deadline = async {
+            match self.deadline {
+                Some(deadline) => sleep(deadline).await,
+                None => pending().await,
+            }
+        };
+        select! {
+            _ = self.finished.notified() => {}
+            _ = deadline => {
+                info!("Deadline reached");
+            }
+        }
+        ping.abort();
+        expire.abort();
+        listen.abort();
+    }
+    async fn ping(&'static self) {
+        if self.probes.lock().await.sent != 0 {
             panic!("Already started pinging!");
         }
-        let mut data: Vec<u8> = vec![0; self.size as usize];
-        let mut timer = if self.interval.is_zero() {
-            interval(Duration::from_nanos(1))
-        } else {
-            interval(self.interval)
+        let period = match self.pacing {
+            Pacing::Flood => FLOOD_INTERVAL,
+            _ if self.interval.is_zero() => Duration::from_nanos(1),
+            _ => self.interval,
         };
+        let mut timer = interval_at(Instant::now() + period, period);
+        let mut last_sent = Instant::now();
         for i in 0..self.count {
-            timer.tick().await;
-            let mut echo_packet = MutableEchoRequestPacket::new(&mut data[..]).unwrap();
-            echo_packet.set_sequence_number(i);
-            echo_packet.set_icmp_type(IcmpTypes::EchoRequest);
-
-            let now = Instant::now();
-            self.starts.write().await.push(now);
-            self.latencies.lock().await.push(None);
+            if ui::wait_resumed().await {
+                timer.reset();
+            }
+            // the first `preload` packages are sent back-to-back
+            if i != 0 && i >= self.preload {
+                self.pace(&mut timer, last_sent).await;
+            }
+            last_sent = Instant::now();
+            let data = self.kind.request(self.identifier, i, self.size);
 
-            self.timeout_handles
-                .lock()
-                .await
-                .push(tokio::spawn(self.timeout(i, listen_handle)));
+            // before sending, the reply might be quicker than us
+            self.expect(i).await;
+            let host = self.host.read().await.clone();
             match self
                 .socket
-                .write_with(|socket| socket.send_to(&data, &self.host))
+                .async_io(Interest::WRITABLE, |socket| socket.send_to(&data, &host))
                 .await
             {
                 Ok(_) => {}
                 Err(e) => {
                     error!("Failed to send packet: {}", e);
-                    self.timeout_handles.lock().await.index(i as usize).abort();
+                    if let Some((sent, _)) = self.take(i).await {
+                        self.finish(sent.elapsed(), Outcome::Error).await;
+                    }
                     continue;
                 }
             }
             debug!(
                 "Sent package {i} to {}",
-                self.host.as_socket_ipv4().unwrap().ip()
+                host.as_socket_ipv4().unwrap().ip()
             );
+            if self.pacing == Pacing::Flood {
+                self.flood_progress(b'.');
+            }
         }
+        // without any probes nothing else would notice
+        self.check_finished().await;
+    }
+    /// Wait for a reply to probe `seq`, which is about to be sent.
+    async fn expect(&self, seq: u16) {
+        // every probe gets the full timeout, only the last one lingers shorter
+        let wait = match seq == self.count - 1 {
+            true => self.reply_wait().await,
+            false => self.timeout,
+        };
+        let now = Instant::now();
+        let mut probes = self.probes.lock().await;
+        probes.sent += 1;
+        let probe = Outstanding {
+            sent: now,
+            answered: false,
+        };
+        probes.outstanding.insert(seq, probe);
+        // only the shorter wait of the last probe can end before the others
+        let at = probes
+            .deadlines
+            .partition_point(|(deadline, _)| *deadline <= now + wait);
+        probes.deadlines.insert(at, (now + wait, seq));
+        drop(probes);
+        self.scheduled.notify_one();
+    }
+    /// Take probe `seq` which got a reply or an error, with when it was sent
+    /// and whether it is the first answer. `None` if it is unknown or finished.
+    async fn take(&self, seq: u16) -> Option<(Instant, bool)> {
+        let mut probes = self.probes.lock().await;
+        match probes.outstanding.get_mut(&seq) {
+            Some(probe) if self.broadcast => {
+                let first = !probe.answered;
+                probe.answered = true;
+                Some((probe.sent, first))
+            }
+            Some(_) => probes
+                .outstanding
+                .remove(&seq)
+                .map(|probe| (probe.sent, true)),
+            None => None,
+        }
+    }
+    /// Count the outcome of a probe which took `elapsed`, and chart it.
+    async fn finish(&self, elapsed: Duration, outcome: Outcome) {
+        self.stats.lock().await.add(outcome);
+        let sample = Sample::new(elapsed, outcome);
+        let _ = self.tx.send(sample).await;
+        self.check_finished().await;
     }
-    pub async fn traceroute(&'static self) -> io::Result<Vec<Option<(Ipv4Addr, Duration)>>> {
+    /// Let `start` return once every probe is sent and finished.
+    async fn check_finished(&self) {
+        let probes = self.probes.lock().await;
+        if probes.sent == self.count && probes.outstanding.is_empty() {
+            self.finished.notify_one();
+        }
+    }
+    /// Time out the probes whose deadline has passed.
+    async fn expire(&'static self) {
+        loop {
+            let next = self
+                .probes
+                .lock()
+                .await
+                .deadlines
+                .front()
+                .map(|(at, _)| *at);
+            select! {
+                _ = sleep_until_some(next) => {}
+                _ = self.scheduled.notified() => continue,
+            }
+            let now = Instant::now();
+            loop {
+                let expired = {
+                    let mut probes = self.probes.lock().await;
+                    match probes.deadlines.front() {
+                        Some((at, _)) if *at <= now => {
+                            let (_, seq) = probes.deadlines.pop_front().unwrap();
+                            Some((seq, probes.outstanding.remove(&seq)))
+                        }
+                        _ => None,
+                    }
+                };
+                match expired {
+                    // answered by every host of a broadcast which was going to
+                    Some((_, Some(probe))) if probe.answered => self.check_finished().await,
+                    Some((seq, Some(probe))) => {
+                        if self.pacing != Pacing::Flood {
+                            error!("Timeout for package {seq}");
+                        }
+                        self.finish(probe.sent.elapsed(), Outcome::Timeout).await;
+                    }
+                    // answered or failed before
+                    Some((_, None)) => {}
+                    None => break,
+                }
+            }
+        }
+    }
+    /// Wait until the next package should be sent.
+    async fn pace(&self, timer: &mut Interval, last_sent: Instant) {
+        match self.pacing {
+            Pacing::Fixed => {
+                timer.tick().await;
+            }
+            Pacing::Jitter(pct) => {
+                let fraction = pct / 100.0;
+                let factor = 1.0 + rand::thread_rng().gen_range(-fraction..=fraction);
+                sleep_until(last_sent + self.interval.mul_f64(factor)).await;
+            }
+            Pacing::Poisson => {
+                // inverse transform sampling of the exponential distribution,
+                // 1 - U is in (0, 1] so the logarithm stays finite
+                let factor = -(1.0 - rand::thread_rng().gen::<f64>()).ln();
+                sleep_until(last_sent + self.interval.mul_f64(factor)).await;
+            }
+            Pacing::Flood => {
+                select! {
+                    _ = self.replied.notified() => {}
+                    _ = timer.tick() => {}
+                }
+            }
+            Pacing::Adaptive => {
+                select! {
+                    _ = self.replied.notified() => {}
+                    _ = sleep(self.reply_wait().await) => {}
+                }
+                sleep_until(last_sent + ADAPTIVE_MIN_INTERVAL).await;
+            }
+        }
+    }
+    /// Print the iputils flood progress: a dot for every package sent,
+    /// a backspace for every reply and an `E` for every error.
+    fn flood_progress(&self, c: u8) {
+        if self.graph {
+            return;
+        }
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(&[c]);
+        let _ = stdout.flush();
+    }
+    /// Probe every hop once, each hop is also sent to `hops` as soon as it answers or times out.
+    pub async fn traceroute(
+        &'static self,
+        hops: Option<&Sender<TraceUpdate>>,
+    ) -> io::Result<Vec<Option<(Ipv4Addr, Duration)>>> {
         let mut result = vec![];
         for ttl in 1..128 {
+            ui::wait_resumed().await;
             let mut data: Vec<u8> = vec![0; self.size as usize];
             let mut echo_packet = MutableIcmpPacket::new(&mut data[..]).unwrap();
             echo_packet.set_icmp_type(IcmpTypes::EchoRequest);
-            self.socket.as_ref().set_ttl(ttl)?;
+            self.socket.get_ref().set_ttl(ttl)?;
 
             let now = Instant::now();
 
+            let host = self.host.read().await.clone();
             match self
                 .socket
-                .write_with(|socket| socket.send_to(&data, &self.host))
+                .async_io(Interest::WRITABLE, |socket| socket.send_to(&data, &host))
                 .await
             {
                 Ok(_) => {}
@@ -236,195 +578,425 @@ impl Pinger {
                     panic!("{:?}", e);
                 }
             };
-            select! {
+            let mut reached = false;
+            let hop = select! {
                 package = self.recv() => {
                     trace!("{:?}", package);
                     match package {
                         Ok(icmp) => {
-                            result.push(Some((
-                                icmp.1,
-                                now.elapsed()
-                            )));
-                            info!("Hop {ttl:>2 }: {:<15 } {:?}", icmp.1, now.elapsed());
-                            break;
+                            self.print_hop(ttl, icmp.1, now.elapsed(), "").await;
+                            reached = true;
+                            Some(Some((icmp.1, now.elapsed())))
                         },
                         Err(IcmpError::TimeExceeded(addr, _)) => {
-                            result.push(Some((
-                                addr,
-                                now.elapsed()
-                            )));
-                            info!("Hop {ttl:>2 }: {addr:<15 } {:?}", now.elapsed());
+                            self.print_hop(ttl, addr, now.elapsed(), "").await;
+                            Some(Some((addr, now.elapsed())))
                         },
                         Err(err) => {
-                            error!("{}", err);
+                            match (self.compat, err.traceroute_flag()) {
+                                (Some(Compat::Iputils), Some((addr, flag))) => {
+                                    self.print_hop(ttl, addr, now.elapsed(), flag).await
+                                }
+                                _ => error!("{}", err),
+                            }
+                            None
                         }
                     }
                 }
                 _ = sleep(self.timeout) => {
-                    info!("Hop {ttl:>2 }: *");
-                    result.push(None);
+                    match self.compat {
+                        Some(Compat::Iputils) => println!("{ttl:>2}  *"),
+                        None => info!("Hop {ttl:>2 }: *"),
+                    }
+                    Some(None)
                 }
             };
+            if let Some(hop) = hop {
+                result.push(hop);
+                if let Some(hops) = hops {
+                    let _ = hops.send(TraceUpdate::Hop(ttl as u8, hop)).await;
+                }
+            }
+            if reached {
+                break;
+            }
 
             debug!(
                 "Sent package {ttl} to {}",
-                self.host.as_socket_ipv4().unwrap().ip()
+                host.as_socket_ipv4().unwrap().ip()
             );
         }
         Ok(result)
     }
-    async fn recv(&'static self) -> Result<(IcmpPacket<'static>, Ipv4Addr), IcmpError> {
-        let mut recv_buf: Vec<MaybeUninit<u8>> = vec![MaybeUninit::uninit(); 1500];
-        let resp_packet = self.socket.read_with(|s| s.recv_from(&mut recv_buf)).await;
-        let (n, remote) = match resp_packet {
-            Ok((n, r)) => (n, r),
-            Err(_e) => {
-                let mut recv_buf: Vec<u8> = vec![0; 1500];
-                let result = self
-                    .socket
-                    .read_with(|s| {
-                        let iov = IoSliceMut::new(recv_buf.as_mut_slice());
-                        let mut cmsg_buffer = vec![0u8; 1500];
-                        recvmsg::<SockaddrStorage>(
-                            s.as_raw_fd(),
-                            [iov].as_mut_slice(),
-                            Some(&mut cmsg_buffer),
-                            MsgFlags::MSG_ERRQUEUE,
-                        )
-                        .map_err(|e| e.into())
-                        .map(|r| r.cmsgs().collect::<Vec<_>>())
-                    })
-                    .await;
-                let result = result?;
-                let icmp = EchoRequestPacket::new(&recv_buf[..]).unwrap();
-                let seq = icmp.get_sequence_number();
-                for msg in result {
-                    match msg {
-                        nix::sys::socket::ControlMessageOwned::Ipv4RecvErr(e, addr) => {
-                            let addr = addr
-                                .map(|a| Ipv4Addr::from((a.sin_addr.s_addr as u32).to_be()))
-                                .unwrap_or_else(|| Ipv4Addr::new(0, 0, 0, 0));
-                            if e.ee_origin == SO_EE_ORIGIN_ICMP {
-                                return Err(IcmpError::from((e, addr, seq)));
-                            } else {
-                                return Err(IcmpError::UnknownOrigin(
-                                    addr,
-                                    seq,
-                                    e.ee_origin,
-                                    e.ee_code,
-                                    e.ee_type,
-                                ));
-                            }
-                        }
-                        _ => {
-                            panic!("Unexpected control message: {:?}", msg);
-                        }
-                    }
-                }
-                panic!("no msg");
+    async fn recv(&'static self) -> Result<(IcmpPacket<'static>, Ipv4Addr, Option<u8>), IcmpError> {
+        loop {
+            let (icmp, remote, ttl) = self.recv_packet().await?;
+            // raw sockets see every ICMP message arriving at this host,
+            // including our own requests to localhost and other processes' replies
+            if self.kind.needs_raw_socket()
+                && (icmp.get_icmp_type() != self.kind.reply_type()
+                    || icmp::identifier(icmp.packet()) != self.identifier)
+            {
+                continue;
             }
+            // a late reply to an address we pinged before `set_host`, or with raw
+            // sockets a reply to another of our pingers, which share the identifier
+            if !self.broadcast && remote != self.host().await {
+                debug!("Ignoring package from {remote}");
+                continue;
+            }
+            return Ok((icmp, remote, ttl));
+        }
+    }
+    /// The next ICMP message, with the TTL of its IP package.
+    async fn recv_packet(
+        &'static self,
+    ) -> Result<(IcmpPacket<'static>, Ipv4Addr, Option<u8>), IcmpError> {
+        let mut recv_buf = vec![0; 1500];
+        let (n, remote, ttl) = match recv_or_error(&self.socket, &mut recv_buf).await? {
+            Received::Package(n, remote, ttl) => (n, remote, ttl),
+            Received::Error(_, _, err) => return Err(err),
         };
-        let mut recv_buf = recv_buf
-            .into_iter()
-            .map(|x| unsafe { x.assume_init() })
-            .collect::<Vec<u8>>();
         recv_buf.truncate(n);
+        if self.kind.needs_raw_socket() {
+            // raw sockets also deliver the IP header
+            let header_length = (recv_buf[0] & 0x0f) as usize * 4;
+            recv_buf.drain(..header_length);
+        }
         let icmp = IcmpPacket::owned(recv_buf).unwrap();
-        return Ok((icmp, *remote.as_socket_ipv4().unwrap().ip()));
+        Ok((icmp, remote, ttl))
     }
     async fn listen(&'static self) {
-        for _i in 0..self.count {
+        loop {
             let icmp = self.recv().await;
             match icmp {
-                Ok((icmp, remote)) => {
-                    match icmp.get_icmp_type() {
-                        IcmpTypes::EchoReply => {
-                            let echo_reply: EchoReplyPacket =
-                                EchoReplyPacket::new(icmp.packet()).unwrap();
-                            let seq = echo_reply.get_sequence_number();
-                            let duration = self.starts.read().await.index(seq as usize).elapsed();
-                            let remote = remote.to_string();
+                Ok((icmp, remote, ttl)) => match icmp.get_icmp_type() {
+                    t if t == self.kind.reply_type() => {
+                        let received_at = icmp::ms_since_midnight();
+                        let seq = icmp::sequence_number(icmp.packet());
+                        let (duration, first) = match self.take(seq).await {
+                            Some((sent, first)) => (sent.elapsed(), first),
+                            None => {
+                                warn!("Received reply for unknown or finished package {seq}");
+                                continue;
+                            }
+                        };
+                        match self.kind {
+                            ProbeKind::Timestamp => {
+                                match TimestampEstimate::from_reply(icmp.packet(), received_at) {
+                                    Some(estimate) => {
+                                        debug!("Timestamp reply #{seq}: {estimate:?}");
+                                        self.timestamps.lock().await.push(estimate);
+                                    }
+                                    None => {
+                                        warn!("Non-standard timestamp in reply #{seq}");
+                                    }
+                                }
+                            }
+                            ProbeKind::Mask => {
+                                let mask = icmp::address_mask(icmp.packet());
+                                info!("Address mask from {remote}: {mask:?}");
+                                *self.address_mask.lock().await = mask;
+                            }
+                            ProbeKind::Echo | ProbeKind::Info => {}
+                        }
+                        if self.pacing == Pacing::Flood {
+                            self.flood_progress(b'\x08');
+                        } else if self.compat == Some(Compat::Iputils) {
+                            // iputils numbers its probes from 1
+                            println!(
+                                "{} bytes from {}: icmp_seq={}{} time={} ms",
+                                icmp.packet().len(),
+                                self.display(remote).await,
+                                seq as u32 + 1,
+                                ttl.map_or(String::new(), |ttl| format!(" ttl={ttl}")),
+                                compat::format_time(duration)
+                            );
+                        } else {
                             info!(
                                 "Received package #{seq} {} bytes from {} in {:?}",
                                 icmp.packet_size(),
                                 remote,
                                 duration
                             );
-                            self.latencies.lock().await[seq as usize] = Some(duration);
+                        }
+                        // further hosts answering a broadcast are only shown
+                        if first {
+                            self.rtts.lock().await.add(duration);
+                            self.replied.notify_one();
+                            self.finish(duration, Outcome::Reply(duration)).await;
+                        }
 
-                            if self.graph {
-                                let mut sent = self.latencies_sent.lock().await;
-                                let mut add = 0;
-                                for l in
-                                    self.latencies.lock().await[*sent as usize..seq as usize].iter()
-                                {
-                                    if l.is_some() {
-                                        add += 1;
-                                        self.tx.send(*l).await.unwrap();
-                                    } else {
-                                        // wait for timeout thread to send this
-                                        break;
-                                    }
+                        if self.once {
+                            self.finished.notify_one();
+                            return;
+                        }
+                    }
+                    _ => {
+                        warn!(
+                            "Received package from {:?}: {:?}",
+                            remote,
+                            icmp.get_icmp_type()
+                        );
+                    }
+                },
+                Err(err) => {
+                    match err {
+                        IcmpError::NetworkUnreachable(_, seq)
+                        | IcmpError::HostUnreachable(_, seq)
+                        | IcmpError::ProtocolUnreachable(_, seq)
+                        | IcmpError::PortUnreachable(_, seq)
+                        | IcmpError::OtherUnreachable(_, seq, _)
+                        | IcmpError::TimeExceeded(_, seq)
+                        | IcmpError::Unknown(_, seq, _, _)
+                        | IcmpError::UnknownOrigin(_, seq, _, _, _) => {
+                            let sent = match self.take(seq).await {
+                                Some((sent, true)) => sent,
+                                _ => {
+                                    debug!("Ignoring error for unknown or finished package {seq}: {err}");
+                                    continue;
                                 }
-                                *sent += add;
-                                drop(sent);
+                            };
+                            match (self.pacing, self.compat, err.iputils()) {
+                                (Pacing::Flood, _, _) => self.flood_progress(b'E'),
+                                (_, Some(Compat::Iputils), Some((from, seq, message))) => println!(
+                                    "From {} icmp_seq={} {message}",
+                                    self.display(from).await,
+                                    seq as u32 + 1
+                                ),
+                                _ => error!("{}", err),
                             }
-
-                            self.timeout_handles
-                                .lock()
-                                .await
-                                .index(seq as usize)
-                                .abort();
+                            self.finish(sent.elapsed(), Outcome::Error).await;
                         }
-                        _ => {
-                            warn!(
-                                "Received package from {:?}: {:?}",
-                                remote,
-                                icmp.get_icmp_type()
-                            );
+                        IcmpError::Io(_) => {
+                            error!("Stopped receiving: {}", err);
+                            return;
                         }
                     }
                 }
-                Err(err) => match err {
-                    IcmpError::NetworkUnreachable(_, seq)
-                    | IcmpError::HostUnreachable(_, seq)
-                    | IcmpError::ProtocolUnreachable(_, seq)
-                    | IcmpError::PortUnreachable(_, seq)
-                    | IcmpError::OtherUnreachable(_, seq, _)
-                    | IcmpError::TimeExceeded(_, seq)
-                    | IcmpError::Unknown(_, seq, _, _)
-                    | IcmpError::UnknownOrigin(_, seq, _, _, _) => {
-                        error!("{}", err);
-                        self.timeout_handles
-                            .lock()
-                            .await
-                            .index(seq as usize)
-                            .abort();
-                    }
-                    IcmpError::Io(_) => {
-                        error!("{}", err);
-                    }
-                },
             }
         }
-        self.finished.notify_one();
     }
-    async fn timeout(&self, seq: u16, listen_handle: &JoinHandle<()>) {
-        sleep(self.timeout).await;
-        error!("Timeout for package {seq}");
+    /// A hop answering the probe with TTL `ttl`, `flag` marks unreachables like traceroute.
+    async fn print_hop(&self, ttl: u32, addr: Ipv4Addr, rtt: Duration, flag: &str) {
+        match self.compat {
+            Some(Compat::Iputils) => {
+                // without -n traceroute shows the address in parentheses, named or not
+                let host = match self.display(addr).await {
+                    host if !self.numeric && host == addr.to_string() => format!("{addr} ({addr})"),
+                    host => host,
+                };
+                let flag = match flag {
+                    "" => String::new(),
+                    flag => format!(" {flag}"),
+                };
+                println!(
+                    "{ttl:>2}  {host}  {:.3} ms{flag}",
+                    rtt.as_secs_f64() * 1000.0
+                );
+            }
+            None => info!("Hop {ttl:>2 }: {addr:<15 } {:?}", rtt),
+        }
+    }
+    /// `name (addr)` like iputils, unless names are turned off or `addr` has none.
+    async fn display(&self, addr: Ipv4Addr) -> String {
+        if self.numeric {
+            return addr.to_string();
+        }
+        let cached = self.names.lock().await.get(&addr).cloned();
+        let name = match cached {
+            Some(name) => name,
+            None => {
+                let name = tokio::task::spawn_blocking(move || compat::reverse_name(addr))
+                    .await
+                    .unwrap();
+                self.names.lock().await.insert(addr, name.clone());
+                name
+            }
+        };
+        compat::format_host(addr, name.as_deref())
+    }
+    /// Time to linger for a reply when nothing else is sent, like iputils after its
+    /// last probe: two times the largest round trip seen, the configured timeout
+    /// before the first reply arrives, but never more than it.
+    async fn reply_wait(&self) -> Duration {
+        let stats = self.stats.lock().await;
+        match stats.replies {
+            0 => self.timeout,
+            _ => (Duration::from_micros(stats.max as u64) * 2).min(self.timeout),
+        }
+    }
+}
 
-        if self.graph {
-            // we can assure that this is the first, non_sent timeout package
-            // we can safely send a none and add one to latencies_sent
-            // if this package is followed by sent packages,
-            // they will be send by the listen thread
-            self.tx.send(None).await.unwrap();
-            *self.latencies_sent.lock().await += 1;
+/// Read the oldest error from the error queue of `socket`,
+/// with the destination and sequence number of the package which caused it.
+pub fn recv_error(socket: &Socket) -> io::Result<(Option<Ipv4Addr>, u16, IcmpError)> {
+    let mut recv_buf: Vec<u8> = vec![0; 1500];
+    let iov = IoSliceMut::new(recv_buf.as_mut_slice());
+    let mut cmsg_buffer = vec![0u8; 1500];
+    let result = recvmsg::<SockaddrStorage>(
+        socket.as_raw_fd(),
+        [iov].as_mut_slice(),
+        Some(&mut cmsg_buffer),
+        MsgFlags::MSG_ERRQUEUE,
+    )?;
+    let destination = result
+        .address
+        .and_then(|addr| addr.as_sockaddr_in().map(|addr| Ipv4Addr::from(addr.ip())));
+    let messages: Vec<_> = result.cmsgs().collect();
+    let icmp = EchoRequestPacket::new(&recv_buf[..]).unwrap();
+    let seq = icmp.get_sequence_number();
+    for msg in messages {
+        match msg {
+            nix::sys::socket::ControlMessageOwned::Ipv4RecvErr(e, addr) => {
+                let addr = addr
+                    .map(|a| Ipv4Addr::from((a.sin_addr.s_addr as u32).to_be()))
+                    .unwrap_or_else(|| Ipv4Addr::new(0, 0, 0, 0));
+                if e.ee_origin == SO_EE_ORIGIN_ICMP {
+                    return Ok((destination, seq, IcmpError::from((e, addr, seq))));
+                } else {
+                    return Ok((
+                        destination,
+                        seq,
+                        IcmpError::UnknownOrigin(addr, seq, e.ee_origin, e.ee_code, e.ee_type),
+                    ));
+                }
+            }
+            // e.g. the TTL asked for with IP_RECVTTL
+            _ => trace!("Ignoring control message {:?}", msg),
         }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "queued error without an extended error message",
+    ))
+}
 
-        if seq == self.count - 1 {
-            listen_handle.abort();
-            self.finished.notify_one();
+/// What `recv_or_error` got from a socket.
+#[derive(Debug)]
+pub enum Received {
+    /// Length, sender and TTL of a package, see `recv_with_ttl`.
+    Package(usize, Ipv4Addr, Option<u8>),
+    /// An error from the error queue, see `recv_error`.
+    Error(Option<Ipv4Addr>, u16, IcmpError),
+}
+
+/// Wait for the next package or queued error of `socket`, whichever comes first.
+/// Queued errors make the socket report `POLLERR`, so they are read as soon as
+/// they arrive instead of only after a receive fails.
+pub async fn recv_or_error(socket: &AsyncFd<Socket>, buf: &mut [u8]) -> io::Result<Received> {
+    loop {
+        let mut guard = socket.ready(Interest::READABLE | Interest::ERROR).await?;
+        if guard.ready().is_error() {
+            match recv_error(guard.get_inner()) {
+                Ok((destination, seq, err)) => return Ok(Received::Error(destination, seq, err)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    // the queue is empty, but the pending error of the socket
+                    // would keep it reporting POLLERR
+                    if let Some(e) = guard.get_inner().take_error()? {
+                        trace!("Cleared socket error: {}", e);
+                    }
+                    guard.clear_ready_matching(Ready::ERROR);
+                }
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => debug!("{}", e),
+                Err(e) => return Err(e),
+            }
+            continue;
+        }
+        match recv_with_ttl(guard.get_inner(), buf) {
+            Ok((n, remote, ttl)) => return Ok(Received::Package(n, remote, ttl)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                guard.clear_ready_matching(Ready::READABLE)
+            }
+            // the pending error of the socket, the error queue reports it
+            Err(e) if is_icmp_error(&e) => trace!("Cleared socket error: {}", e),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Whether `e` is the error the kernel reports on the socket for an ICMP error message.
+pub fn is_icmp_error(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(
+            libc::ENETUNREACH
+                | libc::EHOSTUNREACH
+                | libc::ENOPROTOOPT
+                | libc::ECONNREFUSED
+                | libc::EMSGSIZE
+                | libc::EOPNOTSUPP
+                | libc::EPROTO
+        )
+    )
+}
+
+/// Sleep until `at`, or forever without it.
+pub async fn sleep_until_some(at: Option<Instant>) {
+    match at {
+        Some(at) => sleep_until(at).await,
+        None => pending().await,
+    }
+}
+
+/// Whether `host` is the broadcast address of one of our interfaces.
+pub fn is_broadcast(host: Ipv4Addr) -> io::Result<bool> {
+    let host = SockaddrIn::from(SocketAddrV4::new(host, 0));
+    Ok(getifaddrs()?
+        .filter_map(|ifaddr| ifaddr.broadcast)
+        .any(|addr| addr.as_sockaddr_in() == Some(&host)))
+}
+
+/// Ask for the TTL of received packages, nix has no socket option for it.
+pub fn set_recv_ttl(socket: &Socket) -> io::Result<()> {
+    let enable: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_RECVTTL,
+            &enable as *const _ as *const libc::c_void,
+            mem::size_of_val(&enable) as libc::socklen_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Receive a package with the sender and the TTL from its `IP_TTL` control message.
+pub fn recv_with_ttl(socket: &Socket, buf: &mut [u8]) -> io::Result<(usize, Ipv4Addr, Option<u8>)> {
+    let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // u64 keeps the control messages aligned
+    let mut control = [0u64; 8];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of_val(&addr) as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut ttl = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::IPPROTO_IP && (*cmsg).cmsg_type == libc::IP_TTL {
+                ttl = Some(*(libc::CMSG_DATA(cmsg) as *const libc::c_int) as u8);
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
         }
     }
+    let remote = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+    Ok((n as usize, remote, ttl))
 }
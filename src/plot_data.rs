@@ -1,128 +1,612 @@
 use core::option::Option;
 use core::option::Option::{None, Some};
 
-use itertools::Itertools;
-use std::ops::Add;
-use std::time::Duration;
+use std::borrow::Cow;
+use std::fmt::{self, Display};
+use std::time::{Duration, Instant, SystemTime};
 use tui::style::Style;
-use tui::symbols;
+use tui::symbols::Marker;
 use tui::text::Span;
 use tui::widgets::{Dataset, GraphType, Paragraph};
 
+use crate::history::{Tier, TIERS};
+use crate::stats::Stats;
+
+/// Time ranges the chart can show, in seconds.
+pub const RANGES: [f64; 4] = [60.0, 10.0 * 60.0, 60.0 * 60.0, 24.0 * 60.0 * 60.0];
+/// How long every single reply is kept, older ones only survive in the tiers.
+const RAW_RETENTION: f64 = 10.0 * 60.0;
+
+/// What became of a single probe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Reply(Duration),
+    Timeout,
+    /// The probe failed early, e.g. with an ICMP error.
+    Error,
+}
+
+impl Outcome {
+    pub fn latency(&self) -> Option<Duration> {
+        match self {
+            Outcome::Reply(latency) => Some(*latency),
+            _ => None,
+        }
+    }
+}
+
+/// A finished probe, keyed by the time it was sent.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub sent: SystemTime,
+    pub outcome: Outcome,
+}
+
+impl Sample {
+    /// A probe which was sent `elapsed` ago.
+    pub fn new(elapsed: Duration, outcome: Outcome) -> Self {
+        Sample {
+            sent: SystemTime::now() - elapsed,
+            outcome,
+        }
+    }
+}
+
+/// Something to show on the chart of one series.
+#[derive(Debug)]
+pub enum Update {
+    Sample(Sample),
+    /// Mark the current position, e.g. when the target address changed.
+    Annotation(String),
+}
+
+/// One time slice of the heatmap.
+#[derive(Debug, Clone, Default)]
+pub struct HeatmapColumn {
+    /// Replies in every latency bin, lowest latency first.
+    pub bins: Vec<u64>,
+    /// Bin of the median reply.
+    pub median: Option<usize>,
+    pub replies: usize,
+    pub lost: usize,
+}
+
+/// Insert `item` into `items`, which are sorted by their time.
+fn insert_sorted<T>(items: &mut Vec<(f64, T)>, item: (f64, T)) {
+    let at = items.partition_point(|(x, _)| *x <= item.0);
+    items.insert(at, item);
+}
+
+/// Drop the items before `earliest`.
+fn prune<T>(items: &mut Vec<(f64, T)>, earliest: f64) {
+    let end = items.partition_point(|(x, _)| *x < earliest);
+    items.drain(..end);
+}
+
+/// How latencies are placed on the y axis.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct YAxis {
+    /// Plot the logarithm of the latency, so spikes don't flatten everything else.
+    pub log: bool,
+    /// Fixed bounds in microseconds, instead of the visible latencies plus 10%.
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+impl YAxis {
+    /// Position of a latency in microseconds on the axis.
+    pub fn plot(&self, micros: f64) -> f64 {
+        match self.log {
+            true => micros.max(1.0).log10(),
+            false => micros,
+        }
+    }
+
+    /// Latency in microseconds at a position on the axis.
+    pub fn unplot(&self, y: f64) -> f64 {
+        match self.log {
+            true => 10f64.powf(y),
+            false => y,
+        }
+    }
+}
+
+/// Points the rolling percentiles look at, when no moving average window is set.
+const PERCENTILE_WINDOW: usize = 20;
+
+/// Lines derived from the latency, drawn over it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Overlays {
+    /// Moving average over this many points.
+    pub average: Option<usize>,
+    /// Weight of the newest point in an exponentially weighted moving average.
+    pub ewma: Option<f64>,
+    /// Rolling p50, p95 and p99 over the moving average window.
+    pub percentiles: bool,
+    /// Latency objective in microseconds, drawn as a horizontal line.
+    pub slo: Option<f64>,
+}
+
+/// Which derived line a dataset shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overlay {
+    Average,
+    Ewma,
+    Percentile(u8),
+    Slo,
+}
+
+impl Display for Overlay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Overlay::Average => write!(f, "avg"),
+            Overlay::Ewma => write!(f, "ewma"),
+            Overlay::Percentile(p) => write!(f, "p{p}"),
+            Overlay::Slo => write!(f, "slo"),
+        }
+    }
+}
+
+/// Latency of one series over time.
+/// The x axis is in seconds since the series was created, the y axis in microseconds.
 pub struct PlotData {
     pub display: String,
     pub data: Vec<(f64, f64)>,
     pub style: Style,
+    pub marker: Marker,
+    pub y_axis: YAxis,
+    overlays: Overlays,
+    /// Visible time range in seconds.
     buffer: f64,
-    simple_graphics: bool,
-    idx: f64,
+    created: SystemTime,
+    resolution: Duration,
+    /// Probes waiting to be merged into a single point, see `new`.
+    pending: Vec<(f64, Outcome)>,
+    pending_since: Option<Instant>,
+    losses: Vec<(f64, Outcome)>,
+    /// Aggregates for ranges longer than `RAW_RETENTION`.
+    tiers: Vec<Tier>,
+    annotations: Vec<(f64, String)>,
 }
 
 impl PlotData {
-    pub fn new(display: String, buffer: f64, style: Style, simple_graphics: bool) -> PlotData {
+    /// Probes finishing within `resolution` of each other are merged into a single
+    /// point, so fast pacing modes don't flood the chart. The point shows the worst
+    /// of them: a timeout or an error if there was one, else the slowest reply.
+    pub fn new(
+        display: String,
+        buffer: f64,
+        style: Style,
+        marker: Marker,
+        y_axis: YAxis,
+        overlays: Overlays,
+        resolution: Duration,
+    ) -> PlotData {
         PlotData {
             display,
             data: Vec::with_capacity(150),
             style,
+            marker,
+            y_axis,
+            overlays,
             buffer,
-            simple_graphics,
-            idx: 0.0,
+            created: SystemTime::now(),
+            resolution,
+            pending: vec![],
+            pending_since: None,
+            losses: vec![],
+            tiers: TIERS
+                .iter()
+                .map(|(width, retention)| Tier::new(*width, *retention))
+                .collect(),
+            annotations: vec![],
         }
     }
 
-    pub fn update(&mut self, item: Option<Duration>) {
-        let idx = self.idx;
-        match item {
-            Some(dur) => self.data.push((idx, dur.as_micros() as f64)),
-            None => self.data.push((idx, f64::NAN)),
+    /// Position of `time` on the x axis.
+    fn x(&self, time: SystemTime) -> f64 {
+        time.duration_since(self.created)
+            .unwrap_or_default()
+            .as_secs_f64()
+    }
+
+    fn now(&self) -> f64 {
+        self.x(SystemTime::now())
+    }
+
+    /// Forget everything received so far.
+    pub fn reset(&mut self) {
+        self.data.clear();
+        self.pending.clear();
+        self.pending_since = None;
+        self.losses.clear();
+        self.tiers.iter_mut().for_each(Tier::clear);
+        self.annotations.clear();
+    }
+
+    /// Move `steps` entries through `RANGES`, positive steps show a longer range.
+    pub fn zoom(&mut self, steps: isize) {
+        let current = RANGES
+            .iter()
+            .position(|range| *range >= self.buffer)
+            .unwrap_or(RANGES.len() - 1);
+        let next = (current as isize + steps).clamp(0, RANGES.len() as isize - 1);
+        self.buffer = RANGES[next as usize];
+    }
+
+    /// Visible time range in seconds.
+    pub fn range(&self) -> f64 {
+        self.buffer
+    }
+
+    /// The aggregates to show, `None` while single replies cover the range.
+    fn tier(&self) -> Option<&Tier> {
+        if self.buffer <= RAW_RETENTION {
+            return None;
         }
-        let earliest_timestamp = idx - self.buffer;
-        let last_idx = self
-            .data
+        self.tiers
             .iter()
-            .enumerate()
-            .filter(|(_, (timestamp, _))| *timestamp < earliest_timestamp)
-            .map(|(idx, _)| idx)
-            .last();
-        if let Some(idx) = last_idx {
-            self.data.drain(0..idx).for_each(drop)
+            .find(|tier| tier.retention >= self.buffer)
+            .or_else(|| self.tiers.last())
+    }
+
+    fn earliest_visible(&self) -> f64 {
+        self.now() - self.buffer
+    }
+
+    /// Points inside the visible time range.
+    /// Long ranges show the average of every bucket of their tier.
+    fn visible(&self) -> &[(f64, f64)] {
+        let points = self.kept();
+        &points[self.first_visible(points)..]
+    }
+
+    /// All points of the tier shown, or all single replies.
+    fn kept(&self) -> &[(f64, f64)] {
+        match self.tier() {
+            Some(tier) => &tier.points,
+            None => &self.data,
         }
-        self.idx += 1.0;
     }
 
-    pub fn header_stats(&self) -> Vec<Paragraph> {
-        let ping_header = Paragraph::new(self.display.clone()).style(self.style);
-        let items: Vec<&f64> = self
-            .data
+    fn first_visible(&self, points: &[(f64, f64)]) -> usize {
+        let earliest = self.earliest_visible();
+        points.partition_point(|(x, _)| *x < earliest)
+    }
+
+    /// Visible points, placed on the y axis.
+    pub fn points(&self) -> Cow<'_, [(f64, f64)]> {
+        self.plot(self.visible())
+    }
+
+    /// Place `points` on the y axis, they are only copied for a log scale.
+    pub fn plot<'a>(&self, points: &'a [(f64, f64)]) -> Cow<'a, [(f64, f64)]> {
+        match self.y_axis.log {
+            true => points
+                .iter()
+                .map(|(x, y)| (*x, self.y_axis.plot(*y)))
+                .collect(),
+            false => Cow::Borrowed(points),
+        }
+    }
+
+    /// The enabled overlays over the visible range, placed on the y axis.
+    /// Windows reach back before the visible range, so lines don't start over at its edge.
+    pub fn overlays(&self) -> Vec<(Overlay, Vec<(f64, f64)>)> {
+        let points = self.kept();
+        let start = self.first_visible(points);
+        let window = self.overlays.average.unwrap_or(PERCENTILE_WINDOW).max(1);
+        let plot = |lines: Vec<(f64, f64)>| -> Vec<(f64, f64)> {
+            lines
+                .into_iter()
+                .map(|(x, y)| (x, self.y_axis.plot(y)))
+                .collect()
+        };
+        let mut overlays = vec![];
+
+        if let Some(window) = self.overlays.average {
+            let from = start.saturating_sub(window);
+            let mut sum = 0.0;
+            let mut line = vec![];
+            for (i, (x, y)) in points.iter().enumerate().skip(from) {
+                sum += y;
+                if i >= from + window {
+                    sum -= points[i - window].1;
+                }
+                if i >= start {
+                    line.push((*x, sum / (i + 1 - from).min(window) as f64));
+                }
+            }
+            overlays.push((Overlay::Average, plot(line)));
+        }
+        if let Some(alpha) = self.overlays.ewma {
+            let mut average = None;
+            let mut line = vec![];
+            for (i, (x, y)) in points.iter().enumerate() {
+                let next = match average {
+                    Some(average) => alpha * y + (1.0 - alpha) * average,
+                    None => *y,
+                };
+                average = Some(next);
+                if i >= start {
+                    line.push((*x, next));
+                }
+            }
+            overlays.push((Overlay::Ewma, plot(line)));
+        }
+        if self.overlays.percentiles {
+            let percentiles = [50, 95, 99];
+            let mut lines = vec![vec![]; percentiles.len()];
+            let mut sorted = Vec::with_capacity(window);
+            for i in start..points.len() {
+                sorted.clear();
+                sorted.extend(
+                    points[(i + 1).saturating_sub(window)..=i]
+                        .iter()
+                        .map(|p| p.1),
+                );
+                sorted.sort_unstable_by(f64::total_cmp);
+                for (line, p) in lines.iter_mut().zip(percentiles) {
+                    let rank = (sorted.len() - 1) as f64 * p as f64 / 100.0;
+                    line.push((points[i].0, sorted[rank.round() as usize]));
+                }
+            }
+            for (line, p) in lines.into_iter().zip(percentiles) {
+                overlays.push((Overlay::Percentile(p), plot(line)));
+            }
+        }
+        if let Some(slo) = self.overlays.slo {
+            let [left, right] = self.x_axis_bounds();
+            overlays.push((Overlay::Slo, plot(vec![(left, slo), (right, slo)])));
+        }
+        overlays
+    }
+
+    /// Lowest and highest latency of the visible buckets, only for ranges shown from a tier.
+    pub fn envelope(&self) -> Option<[&[(f64, f64)]; 2]> {
+        let tier = self.tier()?;
+        let earliest = self.earliest_visible();
+        let start = tier.points.partition_point(|(x, _)| *x < earliest);
+        Some([&tier.lows[start..], &tier.highs[start..]])
+    }
+
+    pub fn annotate(&mut self, label: String) {
+        let now = self.now();
+        self.annotations.push((now, label));
+    }
+
+    /// Annotations which are still in the visible part of the chart.
+    pub fn annotations(&self) -> impl Iterator<Item = &(f64, String)> {
+        let earliest = self.earliest_visible();
+        self.annotations.iter().filter(move |(x, _)| *x >= earliest)
+    }
+
+    /// Timeouts and errors in the visible part of the chart.
+    /// Losses from a tier are placed in the middle of their bucket.
+    pub fn losses(&self) -> Vec<(f64, Outcome)> {
+        let earliest = self.earliest_visible();
+        match self.tier() {
+            Some(tier) => tier
+                .since(earliest)
+                .iter()
+                .flat_map(|bucket| {
+                    let middle = bucket.start + tier.width / 2.0;
+                    let timeouts =
+                        (0..bucket.stats.timeouts).map(move |_| (middle, Outcome::Timeout));
+                    let errors = (0..bucket.stats.errors).map(move |_| (middle, Outcome::Error));
+                    timeouts.chain(errors)
+                })
+                .collect(),
+            None => self
+                .losses
+                .iter()
+                .filter(|(x, _)| *x >= earliest)
+                .copied()
+                .collect(),
+        }
+    }
+
+    /// Returns whether the chart changed.
+    pub fn update(&mut self, sample: Sample) -> bool {
+        let x = self.x(sample.sent);
+        self.tiers
+            .iter_mut()
+            .for_each(|tier| tier.add(x, sample.outcome));
+        self.pending.push((x, sample.outcome));
+        self.pending_since.get_or_insert_with(Instant::now);
+        let changed = self.flush_due();
+        let now = self.now();
+        prune(&mut self.data, now - RAW_RETENTION);
+        prune(&mut self.losses, now - RAW_RETENTION);
+        let longest = self
+            .tiers
             .iter()
-            .filter(|(_, x)| !x.is_nan())
-            .sorted_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
-            .map(|(_, v)| v)
-            .collect();
-        if items.is_empty() {
-            return vec![ping_header];
+            .map(|tier| tier.retention)
+            .fold(0.0, f64::max);
+        prune(&mut self.annotations, now - longest);
+        self.tiers.iter_mut().for_each(|tier| tier.prune(now));
+        changed
+    }
+
+    /// Merge the waiting probes once the first of them waited for `resolution`,
+    /// also when no more probes arrive. Returns whether the chart changed.
+    pub fn flush_due(&mut self) -> bool {
+        match self.pending_since {
+            Some(since) if since.elapsed() >= self.resolution => self.flush(),
+            _ => false,
         }
+    }
 
-        let min = **items.first().unwrap();
-        let max = **items.last().unwrap();
-        let avg = items.iter().fold(0f64, |sum, &item| sum + item) / items.len() as f64;
-        let jtr = items.iter().enumerate().fold(0f64, |sum, (idx, &item)| {
-            sum + (*items.get(idx + 1).unwrap_or(&item) - item).abs()
-        }) / (items.len() - 1) as f64;
+    /// Put the probes waiting to be merged on the chart, e.g. when the run ended.
+    /// Returns whether the chart changed.
+    pub fn flush(&mut self) -> bool {
+        self.pending_since = None;
+        if self.pending.is_empty() {
+            return false;
+        }
+        let x = self.pending.iter().map(|(x, _)| x).sum::<f64>() / self.pending.len() as f64;
+        // a loss is worse than any reply, a timeout worse than an error
+        let worst = self
+            .pending
+            .drain(..)
+            .map(|(_, outcome)| outcome)
+            .max_by_key(|outcome| match outcome {
+                Outcome::Reply(latency) => (0, *latency),
+                Outcome::Error => (1, Duration::ZERO),
+                Outcome::Timeout => (2, Duration::ZERO),
+            })
+            .unwrap();
+        match worst {
+            Outcome::Reply(latency) => {
+                insert_sorted(&mut self.data, (x, latency.as_micros() as f64))
+            }
+            loss => insert_sorted(&mut self.losses, (x, loss)),
+        }
+        true
+    }
 
-        let percentile_position = 0.95 * items.len() as f32;
-        let rounded_position = percentile_position.round() as usize;
-        let p95 = items.get(rounded_position).map(|i| **i).unwrap_or(0f64);
+    /// Statistics of the visible range.
+    /// Ranges which show single replies are counted in the buckets of the finest tier,
+    /// so they start at the beginning of a bucket.
+    pub fn stats(&self) -> Stats {
+        let tier = self.tier().unwrap_or(&self.tiers[0]);
+        let mut stats = Stats::default();
+        for bucket in tier.since(self.earliest_visible()) {
+            stats.merge(&bucket.stats);
+        }
+        stats
+    }
 
-        // count timeouts
-        let to = self.data.iter().filter(|(_, x)| x.is_nan()).count();
+    pub fn header_stats(&self) -> Vec<Paragraph> {
+        let ping_header = Paragraph::new(self.display.clone()).style(self.style);
+        let stats = self.stats();
+        let losses = Paragraph::new(match stats.errors {
+            0 => format!("t/o {}", stats.timeouts),
+            errors => format!("t/o {} err {errors}", stats.timeouts),
+        })
+        .style(self.style);
+        let (avg, p95) = match (stats.mean(), stats.quantile(0.95)) {
+            (Some(avg), Some(p95)) => (avg, p95),
+            _ => {
+                let mut header = vec![ping_header];
+                header.extend((0..5).map(|_| Paragraph::new("")));
+                header.push(losses);
+                return header;
+            }
+        };
+        let format = |name: &str, micros: f64| {
+            Paragraph::new(format!("{name} {:?}", Duration::from_micros(micros as u64)))
+                .style(self.style)
+        };
 
         vec![
             ping_header,
-            Paragraph::new(format!("min {:?}", Duration::from_micros(min as u64)))
-                .style(self.style),
-            Paragraph::new(format!("max {:?}", Duration::from_micros(max as u64)))
-                .style(self.style),
-            Paragraph::new(format!("avg {:?}", Duration::from_micros(avg as u64)))
-                .style(self.style),
-            Paragraph::new(format!("jtr {:?}", Duration::from_micros(jtr as u64)))
-                .style(self.style),
-            Paragraph::new(format!("p95 {:?}", Duration::from_micros(p95 as u64)))
-                .style(self.style),
-            Paragraph::new(format!("t/o {:?}", to)).style(self.style),
+            format("min", stats.min),
+            format("max", stats.max),
+            format("avg", avg),
+            format("jtr", stats.jitter()),
+            format("p95", p95),
+            losses,
         ]
     }
 
+    /// Distribution of the visible replies over `buckets` equally wide buckets
+    /// between the fastest and the slowest one.
+    /// Returns the lower bound of every bucket in microseconds with its number of replies.
+    pub fn histogram(&self, buckets: usize) -> Vec<(f64, u64)> {
+        let latencies = self.visible().iter().map(|(_, y)| *y);
+        let min = latencies.clone().fold(f64::INFINITY, f64::min);
+        let max = latencies.clone().fold(0f64, f64::max);
+        if buckets == 0 || min > max {
+            return vec![];
+        }
+        let width = ((max - min) / buckets as f64).max(1.0);
+        let mut counts = vec![0; buckets];
+        for latency in latencies {
+            let bucket = ((latency - min) / width) as usize;
+            counts[bucket.min(buckets - 1)] += 1;
+        }
+        counts
+            .into_iter()
+            .enumerate()
+            .map(|(i, count)| (min + width * i as f64, count))
+            .collect()
+    }
+
+    /// Split the visible window into `columns` time slices and the latencies
+    /// within `bounds` into `rows` bins, like SmokePing does.
+    pub fn heatmap(&self, columns: usize, rows: usize, bounds: [f64; 2]) -> Vec<HeatmapColumn> {
+        let mut heatmap = vec![
+            HeatmapColumn {
+                bins: vec![0; rows],
+                ..Default::default()
+            };
+            columns
+        ];
+        if columns == 0 || rows == 0 {
+            return heatmap;
+        }
+        let earliest = self.earliest_visible();
+        let column =
+            |x: f64| (((x - earliest) / self.buffer * columns as f64) as usize).min(columns - 1);
+        let height = (bounds[1] - bounds[0]).max(1.0);
+        let mut latencies = vec![vec![]; columns];
+        for (x, y) in self.visible() {
+            let y = self.y_axis.plot(*y);
+            let row = ((y - bounds[0]) / height * rows as f64).max(0.0) as usize;
+            let column = column(*x);
+            heatmap[column].bins[row.min(rows - 1)] += 1;
+            heatmap[column].replies += 1;
+            latencies[column].push(row.min(rows - 1));
+        }
+        for (x, _) in self.losses() {
+            heatmap[column(x)].lost += 1;
+        }
+        for (column, mut rows) in heatmap.iter_mut().zip(latencies) {
+            rows.sort_unstable();
+            column.median = rows.get(rows.len() / 2).copied();
+        }
+        heatmap
+    }
+
     pub fn y_axis_bounds(&self) -> [f64; 2] {
         // Find the Y axis bounds for our chart.
         // This is trickier than the x-axis. We iterate through all our PlotData structs
         // and find the min/max of all the values. Then we add a 10% buffer to them.
-        let iter = self.data.iter().map(|v| v.1);
-        let min = iter.clone().fold(f64::INFINITY, |a, b| a.min(b));
-        let max = iter.fold(0f64, |a, b| a.max(b));
+        let [lows, highs] = self.envelope().unwrap_or([self.visible(); 2]);
+        let min = lows
+            .iter()
+            .map(|v| v.1)
+            .fold(f64::INFINITY, |a, b| a.min(b));
+        let max = highs.iter().map(|v| v.1).fold(0f64, |a, b| a.max(b));
+        // keep the objective in view
+        let max = max.max(self.overlays.slo.unwrap_or(0.0));
         // Add a 10% buffer to the top and bottom
         let max_10_percent = (max * 10_f64) / 100_f64;
         let min_10_percent = (min * 10_f64) / 100_f64;
-        [min - min_10_percent, max + max_10_percent]
+        let min = self.y_axis.min.unwrap_or(min - min_10_percent);
+        let max = self.y_axis.max.unwrap_or(max + max_10_percent);
+        [self.y_axis.plot(min), self.y_axis.plot(max)]
     }
 
     pub fn x_axis_bounds(&self) -> [f64; 2] {
-        [
-            if self.idx - self.buffer > 0.0 {
-                self.idx - self.buffer
-            } else {
-                0.0
-            },
-            self.idx,
-        ]
+        let now = self.now();
+        [now - self.buffer, now]
     }
 
+    /// Age of the left edge and the middle, and the wall-clock time (UTC) at the right edge.
     pub fn x_axis_labels(&self, bounds: [f64; 2]) -> Vec<Span> {
-        return vec![
-            Span::raw(format!("{:?}", bounds[0])),
-            // Span::raw(format!("{:?}", midpoint.time())),
-            Span::raw(format!("{:?}", bounds[1])),
-        ];
+        let age = |x: f64| {
+            let age = Duration::from_secs((bounds[1] - x).round() as u64);
+            format!("-{}", humantime::format_duration(age))
+        };
+        let now = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+        vec![
+            Span::raw(age(bounds[0])),
+            Span::raw(age((bounds[0] + bounds[1]) / 2.0)),
+            // 2022-01-01T12:34:56Z
+            Span::raw(now[11..].to_string()),
+        ]
     }
 
     pub fn y_axis_labels(&self, bounds: [f64; 2]) -> Vec<Span> {
@@ -133,24 +617,22 @@ impl PlotData {
         let difference = max - min;
         let num_labels = 7;
         // Split difference into one chunk for each of the 7 labels
-        let increment = Duration::from_micros((difference / num_labels as f64) as u64);
-        let duration = Duration::from_micros(min as u64);
+        let increment = difference / num_labels as f64;
 
         (0..num_labels)
-            .map(|i| Span::raw(format!("{:?}", duration.add(increment * i))))
+            .map(|i| {
+                let micros = self.y_axis.unplot(min + increment * i as f64);
+                Span::raw(format!("{:?}", Duration::from_micros(micros as u64)))
+            })
             .collect()
     }
 
-    pub fn dataset<'a>(&'a self) -> Dataset<'a> {
-        let slice = self.data.as_slice();
+    /// The line of this series through `points`, see `points`.
+    pub fn dataset<'a>(&self, points: &'a [(f64, f64)]) -> Dataset<'a> {
         Dataset::default()
-            .marker(if self.simple_graphics {
-                symbols::Marker::Dot
-            } else {
-                symbols::Marker::Braille
-            })
+            .marker(self.marker)
             .style(self.style)
             .graph_type(GraphType::Line)
-            .data(slice)
+            .data(points)
     }
 }
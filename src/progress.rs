@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use futures::future::pending;
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::time::{interval_at, Instant, Interval};
+
+use crate::plot_data::{Sample, Update};
+use crate::stats::Stats;
+use crate::ui::forward_series;
+
+/// Print the statistics of a single prober, see `spawn_series_progress`.
+pub fn spawn_progress(rx: mpsc::Receiver<Sample>, name: String, every: Option<Duration>) {
+    let (tx, series_rx) = mpsc::channel(10);
+    forward_series(rx, 0, tx);
+    spawn_series_progress(series_rx, vec![name], every);
+}
+
+/// Without the chart: print the statistics of every series over the last `every`,
+/// and the statistics so far whenever SIGQUIT or SIGUSR1 arrives, like ctrl-\ in iputils ping.
+pub fn spawn_series_progress(
+    mut rx: mpsc::Receiver<(usize, Update)>,
+    names: Vec<String>,
+    every: Option<Duration>,
+) {
+    let mut windows = vec![Stats::default(); names.len()];
+    let mut totals = windows.clone();
+    let mut ticks = every.map(|every| interval_at(Instant::now() + every, every));
+    let mut quit = signal(SignalKind::quit()).unwrap();
+    let mut user = signal(SignalKind::user_defined1()).unwrap();
+    let label = move |series: usize, what: &str| match names.len() {
+        1 => what.to_string(),
+        _ => format!("{} {what}", names[series]),
+    };
+
+    tokio::spawn(async move {
+        loop {
+            select! {
+                update = rx.recv() => match update {
+                    Some((series, Update::Sample(sample))) => {
+                        windows[series].add(sample.outcome);
+                        totals[series].add(sample.outcome);
+                    }
+                    Some((_, Update::Annotation(_))) => {}
+                    None => break,
+                },
+                _ = tick(&mut ticks) => {
+                    let last = format!("last {}", humantime::format_duration(every.unwrap()));
+                    for (series, window) in windows.iter_mut().enumerate() {
+                        println!("{}", format_stats(&label(series, &last), window));
+                        *window = Stats::default();
+                    }
+                }
+                _ = quit.recv() => print_totals(&totals, &label),
+                _ = user.recv() => print_totals(&totals, &label),
+            }
+        }
+    });
+}
+
+async fn tick(ticks: &mut Option<Interval>) {
+    match ticks {
+        Some(ticks) => {
+            ticks.tick().await;
+        }
+        None => pending().await,
+    }
+}
+
+/// Statistics so far go to stderr, so they can be told apart from the regular output.
+fn print_totals(totals: &[Stats], label: &impl Fn(usize, &str) -> String) {
+    for (series, total) in totals.iter().enumerate() {
+        eprintln!("{}", format_stats(&label(series, "so far"), total));
+    }
+}
+
+fn format_stats(label: &str, stats: &Stats) -> String {
+    let sent = stats.sent();
+    let loss = match sent {
+        0 => 0.0,
+        sent => (sent - stats.replies) as f64 / sent as f64 * 100.0,
+    };
+    let line = format!(
+        "{label}: {sent} probes, {} replies, {loss:.1}% loss",
+        stats.replies
+    );
+    match stats.mean() {
+        Some(mean) => format!(
+            "{line}, min/avg/max {:.2}/{:.2}/{:.2} ms, jitter {:.2} ms",
+            stats.min / 1000.0,
+            mean / 1000.0,
+            stats.max / 1000.0,
+            stats.jitter() / 1000.0
+        ),
+        None => line,
+    }
+}
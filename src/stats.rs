@@ -0,0 +1,298 @@
+use crate::plot_data::Outcome;
+
+/// Growth of the quantile buckets, every quantile is off by at most 1%.
+const GAMMA: f64 = 1.02;
+
+/// Approximate quantiles with bounded relative error, like DDSketch.
+/// Values are counted in buckets which grow by `GAMMA`, only buckets which
+/// were hit are stored.
+#[derive(Debug, Clone, Default)]
+pub struct Quantiles {
+    /// Bucket index and count, sorted by index.
+    buckets: Vec<(u16, u64)>,
+    count: u64,
+}
+
+impl Quantiles {
+    fn index(value: f64) -> u16 {
+        if value <= 1.0 {
+            0
+        } else {
+            (value.ln() / GAMMA.ln()).ceil() as u16
+        }
+    }
+
+    /// The value in the middle of a bucket, in terms of relative error.
+    fn value(index: u16) -> f64 {
+        match index {
+            0 => 1.0,
+            index => 2.0 * GAMMA.powi(index as i32) / (GAMMA + 1.0),
+        }
+    }
+
+    fn add_count(&mut self, index: u16, count: u64) {
+        match self.buckets.binary_search_by_key(&index, |(i, _)| *i) {
+            Ok(at) => self.buckets[at].1 += count,
+            Err(at) => self.buckets.insert(at, (index, count)),
+        }
+        self.count += count;
+    }
+
+    pub fn add(&mut self, value: f64) {
+        self.add_count(Self::index(value), 1);
+    }
+
+    pub fn merge(&mut self, other: &Quantiles) {
+        for (index, count) in &other.buckets {
+            self.add_count(*index, *count);
+        }
+    }
+
+    /// The `q` quantile (0 to 1) by nearest rank, `None` without values.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (q.clamp(0.0, 1.0) * (self.count - 1) as f64).round() as u64;
+        let mut seen = 0;
+        for (index, count) in &self.buckets {
+            seen += count;
+            if seen > rank {
+                return Some(Self::value(*index));
+            }
+        }
+        self.buckets.last().map(|(index, _)| Self::value(*index))
+    }
+}
+
+/// Statistics of a series of probes, updated in constant time per probe.
+/// Latencies are in microseconds, like in `PlotData`.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub replies: u64,
+    pub timeouts: u64,
+    pub errors: u64,
+    pub min: f64,
+    pub max: f64,
+    /// Welford's running mean and sum of squared differences from it.
+    mean: f64,
+    m2: f64,
+    /// Interarrival jitter as in RFC 3550, section 6.4.1.
+    jitter: f64,
+    last: Option<f64>,
+    quantiles: Quantiles,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            replies: 0,
+            timeouts: 0,
+            errors: 0,
+            min: f64::INFINITY,
+            max: 0.0,
+            mean: 0.0,
+            m2: 0.0,
+            jitter: 0.0,
+            last: None,
+            quantiles: Default::default(),
+        }
+    }
+}
+
+impl Stats {
+    pub fn add(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Reply(latency) => self.add_latency(latency.as_micros() as f64),
+            Outcome::Timeout => self.timeouts += 1,
+            Outcome::Error => self.errors += 1,
+        }
+    }
+
+    pub fn add_latency(&mut self, latency: f64) {
+        self.replies += 1;
+        self.min = self.min.min(latency);
+        self.max = self.max.max(latency);
+        let delta = latency - self.mean;
+        self.mean += delta / self.replies as f64;
+        self.m2 += delta * (latency - self.mean);
+        if let Some(last) = self.last {
+            self.jitter += ((latency - last).abs() - self.jitter) / 16.0;
+        }
+        self.last = Some(latency);
+        self.quantiles.add(latency);
+    }
+
+    /// Combine the statistics of two parts of a series, `other` being the later one.
+    /// The jitter of the combination is the average of both, weighted by their replies.
+    pub fn merge(&mut self, other: &Stats) {
+        let replies = self.replies + other.replies;
+        if other.replies > 0 {
+            let delta = other.mean - self.mean;
+            let weight = other.replies as f64 / replies as f64;
+            self.mean += delta * weight;
+            self.m2 += other.m2 + delta * delta * self.replies as f64 * weight;
+            self.jitter += (other.jitter - self.jitter) * weight;
+            self.last = other.last;
+        }
+        self.replies = replies;
+        self.timeouts += other.timeouts;
+        self.errors += other.errors;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.quantiles.merge(&other.quantiles);
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.replies + self.timeouts + self.errors
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        (self.replies > 0).then_some(self.mean)
+    }
+
+    /// Population standard deviation, like iputils' mdev.
+    pub fn stddev(&self) -> Option<f64> {
+        (self.replies > 0).then(|| (self.m2 / self.replies as f64).sqrt())
+    }
+
+    pub fn jitter(&self) -> f64 {
+        self.jitter
+    }
+
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        self.quantiles.quantile(q)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn stats_of(latencies: &[f64]) -> Stats {
+        let mut stats = Stats::default();
+        for latency in latencies {
+            stats.add_latency(*latency);
+        }
+        stats
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{a} != {b}");
+    }
+
+    #[test]
+    fn quantiles_within_relative_error() {
+        let mut quantiles = Quantiles::default();
+        let values: Vec<f64> = (1..=100_000).map(|v| v as f64).collect();
+        for value in values.iter().rev() {
+            quantiles.add(*value);
+        }
+        for q in [0.0, 0.01, 0.25, 0.5, 0.9, 0.95, 0.99, 0.999, 1.0] {
+            let rank = (q * (values.len() - 1) as f64).round() as usize;
+            let exact = values[rank];
+            let estimate = quantiles.quantile(q).unwrap();
+            assert!(
+                (estimate - exact).abs() / exact <= 0.01,
+                "p{q}: {estimate} for {exact}"
+            );
+        }
+    }
+
+    #[test]
+    fn quantile_buckets_bound_their_values() {
+        for value in [1.5, 2.0, 37.0, 1000.0, 123_456.0, 5e6] {
+            let index = Quantiles::index(value);
+            assert!(GAMMA.powi(index as i32 - 1) < value, "{value}");
+            assert!(value <= GAMMA.powi(index as i32) * (1.0 + 1e-12), "{value}");
+            let estimate = Quantiles::value(index);
+            assert!((estimate - value).abs() / value <= 0.01, "{value}");
+        }
+        // sub-microsecond latencies share the first bucket
+        assert_eq!(Quantiles::index(0.2), 0);
+        assert_eq!(Quantiles::value(0), 1.0);
+    }
+
+    #[test]
+    fn quantiles_of_nothing() {
+        assert_eq!(Quantiles::default().quantile(0.5), None);
+        assert_eq!(Stats::default().quantile(0.5), None);
+    }
+
+    #[test]
+    fn welford_mean_and_stddev() {
+        let stats = stats_of(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]);
+        assert_eq!(stats.replies, 8);
+        assert_close(stats.mean().unwrap(), 5.0);
+        assert_close(stats.stddev().unwrap(), 2.0);
+        assert_eq!(stats.min, 2.0);
+        assert_eq!(stats.max, 9.0);
+        assert_eq!(Stats::default().mean(), None);
+        assert_eq!(Stats::default().stddev(), None);
+    }
+
+    #[test]
+    fn jitter_of_known_deltas() {
+        // every difference is 10, J += (|D| - J) / 16
+        let stats = stats_of(&[10.0, 20.0, 10.0, 20.0]);
+        let mut expected = 0.0;
+        for _ in 0..3 {
+            expected += (10.0 - expected) / 16.0;
+        }
+        assert_close(stats.jitter(), expected);
+        assert_close(stats.jitter(), 1.76025390625);
+        assert_eq!(stats_of(&[7.0; 10]).jitter(), 0.0);
+        assert_eq!(stats_of(&[7.0]).jitter(), 0.0);
+    }
+
+    #[test]
+    fn losses_are_counted() {
+        let mut stats = Stats::default();
+        stats.add(Outcome::Reply(Duration::from_millis(2)));
+        stats.add(Outcome::Timeout);
+        stats.add(Outcome::Error);
+        stats.add(Outcome::Timeout);
+        assert_eq!((stats.replies, stats.timeouts, stats.errors), (1, 2, 1));
+        assert_eq!(stats.sent(), 4);
+        assert_eq!(stats.mean(), Some(2000.0));
+    }
+
+    #[test]
+    fn merge_equals_adding_both() {
+        let first = [120.0, 80.0, 95.0, 3000.0, 101.0];
+        let second = [60.0, 70.0, 65.0, 400.0];
+        let mut merged = stats_of(&first);
+        merged.add(Outcome::Timeout);
+        let mut later = stats_of(&second);
+        later.add(Outcome::Error);
+        merged.merge(&later);
+
+        let mut all = stats_of(&[&first[..], &second[..]].concat());
+        all.add(Outcome::Timeout);
+        all.add(Outcome::Error);
+
+        assert_eq!(
+            (merged.replies, merged.timeouts, merged.errors),
+            (all.replies, all.timeouts, all.errors)
+        );
+        assert_eq!((merged.min, merged.max), (all.min, all.max));
+        assert_close(merged.mean().unwrap(), all.mean().unwrap());
+        assert_close(merged.stddev().unwrap(), all.stddev().unwrap());
+        for q in [0.0, 0.5, 0.95, 1.0] {
+            assert_eq!(merged.quantile(q), all.quantile(q));
+        }
+    }
+
+    #[test]
+    fn merge_with_empty() {
+        let stats = stats_of(&[5.0, 15.0]);
+        let mut merged = Stats::default();
+        merged.merge(&stats);
+        merged.merge(&Stats::default());
+        assert_close(merged.mean().unwrap(), 10.0);
+        assert_close(merged.stddev().unwrap(), 5.0);
+        assert_close(merged.jitter(), stats.jitter());
+        assert_eq!((merged.min, merged.max), (5.0, 15.0));
+    }
+}
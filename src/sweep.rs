@@ -0,0 +1,300 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::Duration;
+
+use futures::future::join;
+use log::debug;
+use tokio::sync::{mpsc::Receiver, Mutex};
+
+use crate::engine::{Engine, Event};
+use crate::stats::Stats;
+
+/// Most addresses a single range may expand to, a /16.
+const MAX_RANGE: u32 = 1 << 16;
+
+/// What is printed about every host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Report {
+    /// `host is alive` as soon as it answers, like plain fping.
+    /// With `only` just the names of live hosts, with `rtt` the first round trip.
+    Alive { only: bool, rtt: bool },
+    /// Loss and min/avg/max at the end, like `fping -c`
+    Count,
+    /// Every round trip at the end, like `fping -C`
+    Vcount,
+}
+
+/// The hosts given on the command line, or else one per line of `file`,
+/// `-` or no file at all reads stdin. Empty lines and `#` comments are skipped.
+/// Address ranges are expanded, see `expand`.
+pub fn read_targets(hosts: Vec<String>, file: Option<&Path>) -> io::Result<Vec<String>> {
+    let mut lines = hosts;
+    if lines.is_empty() || file.is_some() {
+        let reader: Box<dyn BufRead> = match file {
+            Some(path) if path != Path::new("-") => Box::new(BufReader::new(File::open(path)?)),
+            _ => Box::new(BufReader::new(io::stdin())),
+        };
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.split('#').next().unwrap().trim();
+            if !line.is_empty() {
+                lines.push(line.to_string());
+            }
+        }
+    }
+    let mut targets = vec![];
+    for line in lines {
+        targets.extend(expand(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?);
+    }
+    Ok(targets)
+}
+
+/// The addresses of `10.0.0.0/24`, `10.0.0.1-10.0.0.9` or `10.0.0.1-9`, anything
+/// else is a single host. Like `fping -g`, a CIDR block leaves out its network and
+/// broadcast address, unless it is a /31 or /32 which have neither.
+pub fn expand(target: &str) -> Result<Vec<String>, String> {
+    let (first, last) = if let Some((addr, prefix)) = target.split_once('/') {
+        let addr: Ipv4Addr = addr
+            .parse()
+            .map_err(|_| format!("invalid address in {target}"))?;
+        let prefix: u32 = match prefix.parse() {
+            Ok(prefix) if prefix <= 32 => prefix,
+            _ => return Err(format!("invalid prefix length in {target}")),
+        };
+        let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+        let network = u32::from(addr) & mask;
+        let broadcast = network | !mask;
+        match prefix {
+            31 | 32 => (network, broadcast),
+            _ => (network + 1, broadcast - 1),
+        }
+    } else {
+        let range = target
+            .split_once('-')
+            .and_then(|(first, last)| Some((first.parse::<Ipv4Addr>().ok()?, last)));
+        let (first, last) = match range {
+            Some(range) => range,
+            // host names may contain dashes
+            None => return Ok(vec![target.to_string()]),
+        };
+        let last = match (last.parse::<Ipv4Addr>(), last.parse::<u8>()) {
+            (Ok(last), _) => last,
+            (_, Ok(octet)) => {
+                let [a, b, c, _] = first.octets();
+                Ipv4Addr::new(a, b, c, octet)
+            }
+            _ => return Err(format!("invalid end of range in {target}")),
+        };
+        if last < first {
+            return Err(format!("{target} ends before it starts"));
+        }
+        (u32::from(first), u32::from(last))
+    };
+    if last - first >= MAX_RANGE {
+        return Err(format!("{target} has more than {MAX_RANGE} addresses"));
+    }
+    Ok((first..=last)
+        .map(|addr| Ipv4Addr::from(addr).to_string())
+        .collect())
+}
+
+/// Round trip in milliseconds with fping's precision, three significant digits.
+fn format_time(ms: f64) -> String {
+    if ms < 1.0 {
+        format!("{ms:.3}")
+    } else if ms < 10.0 {
+        format!("{ms:.2}")
+    } else if ms < 100.0 {
+        format!("{ms:.1}")
+    } else {
+        format!("{ms:.0}")
+    }
+}
+
+/// What is kept of a target: its statistics, the first round trip and,
+/// only for `Report::Vcount`, every round trip.
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+    pub stats: Stats,
+    pub first: Option<Duration>,
+    pub rtts: Vec<Option<Duration>>,
+    /// All its probes are finished, `Report::Alive` printed it then.
+    pub done: bool,
+}
+
+/// The line fping prints for `name` when it is done with it.
+/// `width` is the length of the longest name, so the colons line up.
+pub fn format_report(report: Report, name: &str, width: usize, summary: &Summary) -> String {
+    let stats = &summary.stats;
+    match report {
+        Report::Alive { only, rtt } => {
+            let (first, line) = match (summary.first, only) {
+                (None, _) => return format!("{name} is unreachable"),
+                (Some(first), true) => (first, name.to_string()),
+                (Some(first), false) => (first, format!("{name} is alive")),
+            };
+            match rtt {
+                true => format!("{line} ({} ms)", format_time(first.as_secs_f64() * 1000.0)),
+                false => line,
+            }
+        }
+        Report::Count => {
+            let sent = stats.sent();
+            let loss = match sent {
+                0 => 0,
+                sent => (sent - stats.replies) * 100 / sent,
+            };
+            let line = format!(
+                "{name:<width$} : xmt/rcv/%loss = {sent}/{}/{loss}%",
+                stats.replies
+            );
+            match stats.mean() {
+                Some(mean) => format!(
+                    "{line}, min/avg/max = {}/{}/{}",
+                    format_time(stats.min / 1000.0),
+                    format_time(mean / 1000.0),
+                    format_time(stats.max / 1000.0)
+                ),
+                None => line,
+            }
+        }
+        Report::Vcount => {
+            let times: Vec<String> = summary
+                .rtts
+                .iter()
+                .map(|rtt| match rtt {
+                    Some(rtt) => format_time(rtt.as_secs_f64() * 1000.0),
+                    None => "-".to_string(),
+                })
+                .collect();
+            format!("{name:<width$} : {}", times.join(" "))
+        }
+    }
+}
+
+/// Run `engine`, which pings the resolved targets, `resolved[i]` being the
+/// index in `targets` of its target `i`, and collect what happens in `summaries`.
+/// `Report::Alive` lines are printed as soon as a target is done, like fping does.
+pub async fn sweep(
+    engine: &'static Engine,
+    mut rx: Receiver<Event>,
+    targets: &[(String, Option<Ipv4Addr>)],
+    resolved: &[usize],
+    report: Report,
+    summaries: &Mutex<Vec<Summary>>,
+) {
+    let collect = async {
+        let mut done = 0;
+        while done < resolved.len() {
+            let event = match rx.recv().await {
+                Some(event) => event,
+                None => break,
+            };
+            let mut summaries = summaries.lock().await;
+            match event {
+                Event::Probe {
+                    target,
+                    index,
+                    outcome,
+                } => {
+                    let summary = &mut summaries[resolved[target]];
+                    summary.stats.add(outcome);
+                    if summary.first.is_none() {
+                        summary.first = outcome.latency();
+                    }
+                    if report == Report::Vcount {
+                        if summary.rtts.len() <= index as usize {
+                            summary.rtts.resize(index as usize + 1, None);
+                        }
+                        summary.rtts[index as usize] = outcome.latency();
+                    }
+                }
+                Event::Done(target) => {
+                    done += 1;
+                    let target = resolved[target];
+                    let name = &targets[target].0;
+                    debug!("Done with {name}");
+                    summaries[target].done = true;
+                    match report {
+                        Report::Alive { only: true, .. } if summaries[target].first.is_none() => {}
+                        Report::Alive { .. } => {
+                            println!("{}", format_report(report, name, 0, &summaries[target]))
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    };
+    join(engine.start(), collect).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_cidr_leaves_out_network_and_broadcast() {
+        assert_eq!(
+            expand("192.0.2.5/30").unwrap(),
+            vec!["192.0.2.5", "192.0.2.6"]
+        );
+        assert_eq!(expand("192.0.2.0/24").unwrap().len(), 254);
+    }
+
+    #[test]
+    fn expand_small_cidr_keeps_every_address() {
+        assert_eq!(
+            expand("192.0.2.7/31").unwrap(),
+            vec!["192.0.2.6", "192.0.2.7"]
+        );
+        assert_eq!(expand("192.0.2.7/32").unwrap(), vec!["192.0.2.7"]);
+    }
+
+    #[test]
+    fn expand_ranges() {
+        assert_eq!(
+            expand("192.0.2.255-192.0.3.1").unwrap(),
+            vec!["192.0.2.255", "192.0.3.0", "192.0.3.1"]
+        );
+        assert_eq!(
+            expand("192.0.2.8-10").unwrap(),
+            vec!["192.0.2.8", "192.0.2.9", "192.0.2.10"]
+        );
+        assert_eq!(expand("192.0.2.8-8").unwrap(), vec!["192.0.2.8"]);
+    }
+
+    #[test]
+    fn expand_rejects_reversed_ranges() {
+        assert!(expand("192.0.2.10-8").is_err());
+        assert!(expand("192.0.3.0-192.0.2.255").is_err());
+    }
+
+    #[test]
+    fn expand_rejects_invalid_input() {
+        assert!(expand("192.0.2.0/33").is_err());
+        assert!(expand("192.0.2/24").is_err());
+        assert!(expand("192.0.2.8-256").is_err());
+    }
+
+    #[test]
+    fn expand_limits_the_size() {
+        assert_eq!(expand("10.0.0.0/16").unwrap().len(), 65534);
+        assert_eq!(expand("10.0.0.0-10.0.255.255").unwrap().len(), 65536);
+        assert!(expand("10.0.0.0-10.1.0.0").is_err());
+        assert_eq!(
+            expand("192.0.2.254-198.51.100.0").unwrap_err(),
+            "192.0.2.254-198.51.100.0 has more than 65536 addresses"
+        );
+        assert!(expand("10.0.0.0/15").is_err());
+        assert!(expand("0.0.0.0/0").is_err());
+    }
+
+    #[test]
+    fn expand_keeps_host_names() {
+        assert_eq!(expand("my-host.example").unwrap(), vec!["my-host.example"]);
+        assert_eq!(expand("192.0.2.1").unwrap(), vec!["192.0.2.1"]);
+    }
+}
@@ -0,0 +1,118 @@
+use std::fmt::{self, Display};
+use std::io;
+use std::net::SocketAddr;
+
+use crate::plot_data::{Outcome, Sample};
+use crate::ui;
+use log::{debug, error, info};
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc::Sender, Mutex},
+    time::{interval, timeout, Duration, Instant},
+};
+
+/// What a single connection attempt found out about the port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpState {
+    /// The handshake completed.
+    Open,
+    /// The host answered with a RST.
+    Closed,
+    /// Nothing came back before the timeout.
+    Filtered,
+    /// The connection failed in another way, e.g. host unreachable.
+    Error,
+}
+
+impl Display for TcpState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpState::Open => write!(f, "open"),
+            TcpState::Closed => write!(f, "closed"),
+            TcpState::Filtered => write!(f, "filtered"),
+            TcpState::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// Measures TCP handshake time, the same way `Pinger` measures echo replies.
+#[derive(Debug)]
+pub struct TcpPinger {
+    host: SocketAddr,
+    count: u16,
+    timeout: Duration,
+    interval: Duration,
+    /// Round trip of the handshake, or of the RST for closed ports.
+    pub latencies: Mutex<Vec<Option<Duration>>>,
+    pub states: Mutex<Vec<Option<TcpState>>>,
+    tx: Sender<Sample>,
+}
+
+impl TcpPinger {
+    pub fn new(
+        host: SocketAddr,
+        count: u16,
+        timeout: Duration,
+        interval: Duration,
+        tx: Sender<Sample>,
+    ) -> Self {
+        TcpPinger {
+            host,
+            count,
+            timeout,
+            interval,
+            latencies: Default::default(),
+            states: Default::default(),
+            tx,
+        }
+    }
+    pub async fn start(&'static self) {
+        let mut timer = if self.interval.is_zero() {
+            interval(Duration::from_nanos(1))
+        } else {
+            interval(self.interval)
+        };
+        let mut handles = vec![];
+        for seq in 0..self.count {
+            if ui::wait_resumed().await {
+                timer.reset();
+            }
+            timer.tick().await;
+            self.latencies.lock().await.push(None);
+            self.states.lock().await.push(None);
+            handles.push(tokio::spawn(self.probe(seq)));
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    }
+    async fn probe(&'static self, seq: u16) {
+        let start = Instant::now();
+        debug!("Connecting to {} #{seq}", self.host);
+        let (state, latency) = match timeout(self.timeout, TcpStream::connect(self.host)).await {
+            Ok(Ok(_stream)) => (TcpState::Open, Some(start.elapsed())),
+            Ok(Err(e)) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                (TcpState::Closed, Some(start.elapsed()))
+            }
+            Ok(Err(e)) => {
+                error!("Connection #{seq} to {} failed: {}", self.host, e);
+                (TcpState::Error, None)
+            }
+            Err(_) => (TcpState::Filtered, None),
+        };
+        match latency {
+            Some(latency) => info!("Port {} {state} #{seq} in {:?}", self.host, latency),
+            None => info!("Port {} {state} #{seq}", self.host),
+        }
+        self.latencies.lock().await[seq as usize] = latency;
+        self.states.lock().await[seq as usize] = Some(state);
+        let outcome = match (state, latency) {
+            (TcpState::Open, Some(latency)) => Outcome::Reply(latency),
+            // a RST answers quickly, but the handshake failed
+            (TcpState::Closed | TcpState::Error, _) => Outcome::Error,
+            (_, _) => Outcome::Timeout,
+        };
+        let sample = Sample::new(start.elapsed(), outcome);
+        let _ = self.tx.send(sample).await;
+    }
+}
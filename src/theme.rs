@@ -0,0 +1,109 @@
+use std::env;
+use std::str::FromStr;
+
+use clap::ValueEnum;
+use tui::style::{Color, Style};
+use tui::symbols;
+
+/// Colors of the series when several targets share a chart.
+pub const SERIES_COLORS: [Color; 6] = [
+    Color::Gray,
+    Color::Cyan,
+    Color::Green,
+    Color::Magenta,
+    Color::Blue,
+    Color::Red,
+];
+
+/// How the points of a series are drawn.
+#[derive(Clone, Copy, ValueEnum, Debug, PartialEq, Eq)]
+pub enum Marker {
+    /// Braille patterns, the finest resolution
+    Braille,
+    /// A dot per point, for terminals without braille glyphs
+    Dot,
+    /// A full block per point, readable even on serial consoles
+    Block,
+}
+
+impl From<Marker> for symbols::Marker {
+    fn from(marker: Marker) -> Self {
+        match marker {
+            Marker::Braille => symbols::Marker::Braille,
+            Marker::Dot => symbols::Marker::Dot,
+            Marker::Block => symbols::Marker::Block,
+        }
+    }
+}
+
+/// Colors given to the series in order, repeating when there are more series.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette(pub Vec<Color>);
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette(SERIES_COLORS.to_vec())
+    }
+}
+
+impl Palette {
+    pub fn style(&self, series: usize) -> Style {
+        Style::default().fg(self.0[series % self.0.len()])
+    }
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    /// A comma separated list of color names or `#rrggbb` values.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let colors = s
+            .split(',')
+            .map(|color| parse_color(color.trim()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Palette(colors))
+    }
+}
+
+fn parse_color(s: &str) -> Result<Color, String> {
+    if let Some(hex) = s.strip_prefix('#') {
+        let value = match hex.len() {
+            6 => u32::from_str_radix(hex, 16).ok(),
+            _ => None,
+        };
+        return match value {
+            Some(value) => Ok(Color::Rgb(
+                (value >> 16) as u8,
+                (value >> 8) as u8,
+                value as u8,
+            )),
+            None => Err(format!("invalid color {s}, expected #rrggbb")),
+        };
+    }
+    let color = match s.to_ascii_lowercase().replace(['-', '_'], "").as_str() {
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "gray" | "grey" => Color::Gray,
+        "darkgray" | "darkgrey" => Color::DarkGray,
+        "lightred" => Color::LightRed,
+        "lightgreen" => Color::LightGreen,
+        "lightyellow" => Color::LightYellow,
+        "lightblue" => Color::LightBlue,
+        "lightmagenta" => Color::LightMagenta,
+        "lightcyan" => Color::LightCyan,
+        "white" => Color::White,
+        _ => return Err(format!("unknown color {s}")),
+    };
+    Ok(color)
+}
+
+/// Whether output should be monochrome, because of `--no-color` or a
+/// non-empty `NO_COLOR` environment variable (https://no-color.org).
+pub fn no_color(flag: bool) -> bool {
+    flag || env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+}
@@ -0,0 +1,123 @@
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+/// How many round trips the sparkline of a hop shows.
+const HISTORY: usize = 120;
+
+/// Something to show on the traceroute view of one target.
+#[derive(Debug)]
+pub enum TraceUpdate {
+    /// The probe with this TTL was answered from the address after the duration, or timed out.
+    Hop(u8, Option<(Ipv4Addr, Duration)>),
+    /// A trace reached the target or gave up, deeper hops of earlier traces are gone.
+    Finished,
+    /// Reverse lookup of an address.
+    Name(Ipv4Addr, String),
+}
+
+/// Everything known about one hop.
+#[derive(Debug, Clone, Default)]
+pub struct Hop {
+    /// The address which answered last.
+    pub addr: Option<Ipv4Addr>,
+    pub name: Option<String>,
+    pub sent: u64,
+    pub received: u64,
+    /// Recent round trips, `None` for timeouts.
+    pub history: VecDeque<Option<Duration>>,
+}
+
+impl Hop {
+    pub fn last(&self) -> Option<Duration> {
+        self.history.back().copied().flatten()
+    }
+    pub fn loss(&self) -> f64 {
+        match self.sent {
+            0 => 0.0,
+            sent => (sent - self.received) as f64 / sent as f64 * 100.0,
+        }
+    }
+    /// Recent round trips in microseconds, timeouts are 0 so they show as gaps.
+    pub fn sparkline(&self) -> Vec<u64> {
+        self.history
+            .iter()
+            .map(|rtt| rtt.map_or(0, |rtt| rtt.as_micros() as u64))
+            .collect()
+    }
+}
+
+/// The hops towards one target, by TTL.
+#[derive(Debug, Clone)]
+pub struct TraceData {
+    pub display: String,
+    pub hops: Vec<Hop>,
+    /// Deepest TTL probed by the current trace.
+    deepest: usize,
+}
+
+impl TraceData {
+    pub fn new(display: String) -> Self {
+        TraceData {
+            display,
+            hops: vec![],
+            deepest: 0,
+        }
+    }
+    pub fn reset(&mut self) {
+        self.hops.clear();
+        self.deepest = 0;
+    }
+    pub fn update(&mut self, update: TraceUpdate) {
+        match update {
+            TraceUpdate::Hop(ttl, reply) => {
+                let index = ttl.max(1) as usize - 1;
+                if self.hops.len() <= index {
+                    self.hops.resize_with(index + 1, Hop::default);
+                }
+                self.deepest = self.deepest.max(index + 1);
+                let known = self.known(reply.map(|(addr, _)| addr));
+                let hop = &mut self.hops[index];
+                hop.sent += 1;
+                if hop.history.len() >= HISTORY {
+                    hop.history.pop_front();
+                }
+                hop.history.push_back(reply.map(|(_, rtt)| rtt));
+                if let Some((addr, _)) = reply {
+                    hop.received += 1;
+                    if hop.addr != Some(addr) {
+                        hop.addr = Some(addr);
+                        hop.name = known;
+                    }
+                }
+            }
+            TraceUpdate::Finished => {
+                self.hops.truncate(self.deepest);
+                self.deepest = 0;
+            }
+            TraceUpdate::Name(addr, name) => {
+                for hop in self.hops.iter_mut().filter(|hop| hop.addr == Some(addr)) {
+                    hop.name = Some(name.clone());
+                }
+            }
+        }
+    }
+    /// The name of `addr` if another hop already has it.
+    fn known(&self, addr: Option<Ipv4Addr>) -> Option<String> {
+        let addr = addr?;
+        self.hops
+            .iter()
+            .find(|hop| hop.addr == Some(addr))
+            .and_then(|hop| hop.name.clone())
+    }
+    /// Largest round trip of any hop in microseconds, so all sparklines share a scale.
+    pub fn max_rtt(&self) -> u64 {
+        self.hops
+            .iter()
+            .flat_map(|hop| hop.history.iter().flatten())
+            .map(|rtt| rtt.as_micros() as u64)
+            .max()
+            .unwrap_or(0)
+            .max(1)
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::pinger::is_icmp_error;
+use crate::plot_data::{Outcome, Sample};
+use crate::ui;
+use log::{debug, error, info, warn};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc::Sender, Mutex, RwLock},
+    time::{interval, sleep, Duration, Instant},
+};
+
+/// Seconds between the NTP epoch (1900) and the unix epoch (1970).
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+/// Smallest unauthenticated sender packet: sequence, timestamp, error estimate.
+pub const SENDER_MIN_SIZE: usize = 14;
+/// Smallest unauthenticated reflector packet, see RFC 5357 section 4.2.1.
+/// Senders pad their packets to this size so the reflector never has to
+/// answer with more bytes than it received.
+pub const REFLECTOR_MIN_SIZE: usize = 41;
+/// Senders the reflector has not heard from for this long start a new session.
+const SESSION_IDLE: Duration = Duration::from_secs(60);
+/// Most sessions the reflector keeps track of at once.
+const MAX_SESSIONS: usize = 4096;
+/// Error estimate with the S bit unset (clock not known to be synchronized),
+/// scale 0 and multiplier 1, which is the smallest valid value.
+const ERROR_ESTIMATE: u16 = 0x0001;
+
+/// Current time as a 64 bit NTP timestamp, like TWAMP uses.
+fn ntp_now() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let seconds = now.as_secs() + NTP_UNIX_OFFSET;
+    let fraction = ((now.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    (seconds << 32) | fraction
+}
+
+/// `a - b` of two NTP timestamps in milliseconds.
+fn ntp_diff_ms(a: u64, b: u64) -> f64 {
+    a.wrapping_sub(b) as i64 as f64 / (1u64 << 32) as f64 * 1000.0
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+/// Unauthenticated TWAMP-light test packet as sent by the session sender,
+/// zero padded to `size`.
+pub fn sender_packet(seq: u32, size: usize) -> Vec<u8> {
+    let mut packet = vec![0; size.max(SENDER_MIN_SIZE)];
+    packet[0..4].copy_from_slice(&seq.to_be_bytes());
+    packet[4..12].copy_from_slice(&ntp_now().to_be_bytes());
+    packet[12..14].copy_from_slice(&ERROR_ESTIMATE.to_be_bytes());
+    packet
+}
+
+/// Reflect a sender packet received at `received`.
+/// The reply is exactly as large as the request, which has to hold at least
+/// `REFLECTOR_MIN_SIZE` bytes.
+pub fn reflector_packet(seq: u32, received: u64, request: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0; request.len()];
+    packet[0..4].copy_from_slice(&seq.to_be_bytes());
+    packet[12..14].copy_from_slice(&ERROR_ESTIMATE.to_be_bytes());
+    packet[16..24].copy_from_slice(&received.to_be_bytes());
+    // sender sequence number, timestamp and error estimate
+    packet[24..38].copy_from_slice(&request[0..14]);
+    // the sender TTL would need IP_RECVTTL, we report 0 for unknown
+    packet[40] = 0;
+    packet[4..12].copy_from_slice(&ntp_now().to_be_bytes());
+    packet
+}
+
+/// The fields of a reflected packet `UdpPinger` needs.
+#[derive(Debug, Clone, Copy)]
+pub struct ReflectedPacket {
+    pub seq: u32,
+    pub transmit: u64,
+    pub receive: u64,
+    pub sender_seq: u32,
+    pub sender_timestamp: u64,
+}
+
+impl ReflectedPacket {
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < REFLECTOR_MIN_SIZE {
+            return None;
+        }
+        Some(ReflectedPacket {
+            seq: read_u32(buf, 0),
+            transmit: read_u64(buf, 4),
+            receive: read_u64(buf, 16),
+            sender_seq: read_u32(buf, 24),
+            sender_timestamp: read_u64(buf, 28),
+        })
+    }
+}
+
+/// Delay of each direction in milliseconds, only meaningful with synchronized clocks.
+#[derive(Debug, Clone, Copy)]
+pub struct OneWayDelay {
+    pub forward: f64,
+    pub backward: f64,
+}
+
+/// Sends TWAMP-light test packets (or plain RFC 862 echo requests)
+/// and measures the round trip of their reflections.
+#[derive(Debug)]
+pub struct UdpPinger {
+    socket: UdpSocket,
+    host: SocketAddr,
+    count: u16,
+    size: usize,
+    echo: bool,
+    timeout: Duration,
+    interval: Duration,
+    starts: RwLock<Vec<Instant>>,
+    pub latencies: Mutex<Vec<Option<Duration>>>,
+    pub one_way: Mutex<Vec<OneWayDelay>>,
+    reflector_seqs: Mutex<Vec<u32>>,
+    tx: Sender<Sample>,
+}
+
+impl UdpPinger {
+    pub async fn new(
+        host: SocketAddr,
+        count: u16,
+        size: usize,
+        echo: bool,
+        timeout: Duration,
+        interval: Duration,
+        tx: Sender<Sample>,
+    ) -> io::Result<Self> {
+        let local: SocketAddr = if host.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(host).await?;
+        Ok(UdpPinger {
+            socket,
+            host,
+            count,
+            size,
+            echo,
+            timeout,
+            interval,
+            starts: Default::default(),
+            latencies: Default::default(),
+            one_way: Default::default(),
+            reflector_seqs: Default::default(),
+            tx,
+        })
+    }
+    pub async fn start(&'static self) {
+        let listen = tokio::spawn(self.listen());
+        let mut timer = if self.interval.is_zero() {
+            interval(Duration::from_nanos(1))
+        } else {
+            interval(self.interval)
+        };
+        for seq in 0..self.count {
+            if ui::wait_resumed().await {
+                timer.reset();
+            }
+            timer.tick().await;
+            let size = match self.echo {
+                true => self.size,
+                // room for the reflector's reply
+                false => self.size.max(REFLECTOR_MIN_SIZE),
+            };
+            let packet = sender_packet(seq as u32, size);
+            self.starts.write().await.push(Instant::now());
+            self.latencies.lock().await.push(None);
+            match self.socket.send(&packet).await {
+                Ok(_) => debug!("Sent package {seq} to {}", self.host),
+                Err(e) => error!("Failed to send packet: {}", e),
+            }
+            tokio::spawn(self.timeout(seq));
+        }
+        sleep(self.timeout).await;
+        listen.abort();
+    }
+    async fn listen(&'static self) {
+        let mut buf = vec![0; 65536];
+        loop {
+            let n = match self.socket.recv(&mut buf).await {
+                Ok(n) => n,
+                // an ICMP error for an earlier test packet, e.g. a closed port
+                Err(e) if is_icmp_error(&e) => {
+                    error!("{}", e);
+                    continue;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Stopped receiving: {}", e);
+                    return;
+                }
+            };
+            let received = ntp_now();
+            let seq = if self.echo {
+                if n < SENDER_MIN_SIZE {
+                    warn!("Received truncated echo of {n} bytes");
+                    continue;
+                }
+                read_u32(&buf, 0)
+            } else {
+                match ReflectedPacket::parse(&buf[..n]) {
+                    Some(reflected) => {
+                        let delay = OneWayDelay {
+                            forward: ntp_diff_ms(reflected.receive, reflected.sender_timestamp),
+                            backward: ntp_diff_ms(received, reflected.transmit),
+                        };
+                        debug!("Reflected package {}: {delay:?}", reflected.sender_seq);
+                        self.one_way.lock().await.push(delay);
+                        self.reflector_seqs.lock().await.push(reflected.seq);
+                        reflected.sender_seq
+                    }
+                    None => {
+                        warn!("Received {n} bytes which are not a TWAMP reflector packet");
+                        continue;
+                    }
+                }
+            };
+            let start = match self.starts.read().await.get(seq as usize) {
+                Some(start) => *start,
+                None => {
+                    warn!("Received reply for unknown package {seq}");
+                    continue;
+                }
+            };
+            let duration = start.elapsed();
+            info!(
+                "Received package #{seq} {n} bytes from {} in {:?}",
+                self.host, duration
+            );
+            let mut latencies = self.latencies.lock().await;
+            if latencies[seq as usize].is_none() {
+                let sample = Sample::new(duration, Outcome::Reply(duration));
+                let _ = self.tx.send(sample).await;
+            }
+            latencies[seq as usize] = Some(duration);
+        }
+    }
+    async fn timeout(&'static self, seq: u16) {
+        sleep(self.timeout).await;
+        if self.latencies.lock().await[seq as usize].is_none() {
+            error!("Timeout for package {seq}");
+            let elapsed = self.starts.read().await[seq as usize].elapsed();
+            let _ = self.tx.send(Sample::new(elapsed, Outcome::Timeout)).await;
+        }
+    }
+    /// Split the lost packages into lost on the way to the reflector and
+    /// lost on the way back.
+    /// The reflector numbers its replies, so gaps in its sequence numbers
+    /// are replies which never arrived.
+    pub async fn loss_by_direction(&self) -> (usize, usize) {
+        let latencies = self.latencies.lock().await;
+        let lost = latencies.iter().filter(|l| l.is_none()).count();
+        split_loss(lost, &self.reflector_seqs.lock().await)
+    }
+}
+
+/// Split `lost` packages into forward and backward losses, by the gaps in the
+/// sequence numbers of the reflector packets which arrived.
+fn split_loss(lost: usize, reflector_seqs: &[u32]) -> (usize, usize) {
+    let backward = match (reflector_seqs.iter().min(), reflector_seqs.iter().max()) {
+        (Some(min), Some(max)) => ((max - min) as usize + 1).saturating_sub(reflector_seqs.len()),
+        _ => 0,
+    };
+    (lost.saturating_sub(backward), backward.min(lost))
+}
+
+/// Answer TWAMP-light test packets arriving at `addr` until receiving fails.
+pub async fn reflect(addr: SocketAddr) -> io::Result<()> {
+    let socket = UdpSocket::bind(addr).await?;
+    info!("Reflecting on {}", socket.local_addr()?);
+    // every sender gets its own sequence numbers, with the time it was last heard from
+    let mut sessions: HashMap<SocketAddr, (u32, Instant)> = HashMap::new();
+    let mut buf = vec![0; 65536];
+    loop {
+        let (n, peer) = socket.recv_from(&mut buf).await?;
+        let received = ntp_now();
+        // shorter requests would make us answer with more than we got
+        if n < REFLECTOR_MIN_SIZE {
+            warn!("Ignoring {n} bytes from {peer}, too short for a test packet");
+            continue;
+        }
+        let now = Instant::now();
+        if sessions.len() >= MAX_SESSIONS && !sessions.contains_key(&peer) {
+            sessions.retain(|_, (_, seen)| now.duration_since(*seen) < SESSION_IDLE);
+            if sessions.len() >= MAX_SESSIONS {
+                warn!("Ignoring {peer}, already reflecting for {MAX_SESSIONS} senders");
+                continue;
+            }
+        }
+        let (seq, seen) = sessions.entry(peer).or_insert((0, now));
+        if now.duration_since(*seen) >= SESSION_IDLE {
+            *seq = 0;
+        }
+        *seen = now;
+        let reply = reflector_packet(*seq, received, &buf[..n]);
+        *seq = seq.wrapping_add(1);
+        // e.g. a firewall or a spoofed sender, the others are still served
+        match socket.send_to(&reply, peer).await {
+            Ok(_) => debug!("Reflected package {} from {peer}", read_u32(&buf, 0)),
+            Err(e) => warn!("Failed to reflect package to {peer}: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sender_packet_layout() {
+        let packet = sender_packet(0x01020304, 60);
+        assert_eq!(packet.len(), 60);
+        assert_eq!(read_u32(&packet, 0), 0x01020304);
+        assert_eq!(&packet[12..14], &ERROR_ESTIMATE.to_be_bytes());
+        assert!(packet[14..].iter().all(|b| *b == 0));
+        let sent = read_u64(&packet, 4);
+        assert!(ntp_diff_ms(ntp_now(), sent).abs() < 1000.0);
+        // the sequence number and timestamps always fit
+        assert_eq!(sender_packet(1, 0).len(), SENDER_MIN_SIZE);
+    }
+
+    #[test]
+    fn reflector_packet_answers_request() {
+        let request = sender_packet(7, REFLECTOR_MIN_SIZE + 9);
+        let received = ntp_now();
+        let reply = reflector_packet(3, received, &request);
+        // never more than was received
+        assert_eq!(reply.len(), request.len());
+        assert_eq!(&reply[24..38], &request[0..14]);
+        let reflected = ReflectedPacket::parse(&reply).unwrap();
+        assert_eq!(reflected.seq, 3);
+        assert_eq!(reflected.receive, received);
+        assert_eq!(reflected.sender_seq, 7);
+        assert_eq!(reflected.sender_timestamp, read_u64(&request, 4));
+        assert!(ntp_diff_ms(reflected.transmit, reflected.receive) >= 0.0);
+    }
+
+    #[test]
+    fn parse_rejects_short_packets() {
+        let request = sender_packet(7, REFLECTOR_MIN_SIZE);
+        let reply = reflector_packet(0, ntp_now(), &request);
+        assert!(ReflectedPacket::parse(&reply).is_some());
+        assert!(ReflectedPacket::parse(&reply[..REFLECTOR_MIN_SIZE - 1]).is_none());
+    }
+
+    #[test]
+    fn ntp_diff_in_milliseconds() {
+        let second = 1u64 << 32;
+        assert_eq!(ntp_diff_ms(3 * second, second), 2000.0);
+        assert_eq!(ntp_diff_ms(second, 3 * second), -2000.0);
+        assert_eq!(ntp_diff_ms(second + second / 4, second), 250.0);
+        // across the wrap of the 32 bit seconds in 2036
+        assert_eq!(ntp_diff_ms(second / 2, u64::MAX - second / 2 + 1), 1000.0);
+    }
+
+    #[test]
+    fn ntp_now_is_after_1970() {
+        let seconds = ntp_now() >> 32;
+        assert!(seconds > NTP_UNIX_OFFSET);
+    }
+
+    #[test]
+    fn split_loss_by_reflector_gaps() {
+        // nothing came back at all, the direction is unknown
+        assert_eq!(split_loss(3, &[]), (3, 0));
+        // the reflector answered 0 to 4, but 1 and 3 never arrived
+        assert_eq!(split_loss(3, &[0, 2, 4]), (1, 2));
+        assert_eq!(split_loss(0, &[0, 1, 2]), (0, 0));
+        // reordered replies
+        assert_eq!(split_loss(1, &[2, 0]), (0, 1));
+        // never more backward losses than losses
+        assert_eq!(split_loss(1, &[0, 5]), (0, 1));
+    }
+}
@@ -0,0 +1,870 @@
+use std::collections::HashSet;
+use std::io::{self, Stdout};
+use std::panic;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crossterm::{
+    cursor::{Hide, Show},
+    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use dns_lookup::lookup_addr;
+use log::{error, Level};
+use tokio::select;
+use tokio::sync::{mpsc, Notify};
+use tokio::time::{interval, MissedTickBehavior};
+use tui::backend::CrosstermBackend;
+use tui::buffer::Buffer;
+use tui::layout::{Constraint, Direction, Layout, Rect};
+use tui::style::{Color, Style};
+use tui::symbols;
+use tui::text::{Span, Spans};
+use tui::widgets::{
+    Axis, BarChart, Block, Borders, Chart, Dataset, GraphType, Paragraph, Sparkline, Widget,
+};
+use tui::{Frame, Terminal};
+
+use crate::logger;
+use crate::plot_data::{HeatmapColumn, Outcome, Overlay, PlotData, Sample, Update};
+use crate::trace_data::{TraceData, TraceUpdate};
+
+const HELP: &str =
+    "q quit  p pause  r reset  v view  +/- range  1-9 toggle series  ↑/↓ scroll events";
+const TRACE_HELP: &str = "q quit  p pause  r reset  ↑/↓ scroll events";
+/// Height of the event pane, including its border.
+const EVENTS_HEIGHT: u16 = 8;
+/// How often points merged from several probes are checked for being due.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Whether the terminal is in raw mode on the alternate screen.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static PAUSED: AtomicBool = AtomicBool::new(false);
+static MONOCHROME: AtomicBool = AtomicBool::new(false);
+static RESUMED: Notify = Notify::const_new();
+static QUIT: Notify = Notify::const_new();
+
+/// Switch to the alternate screen and raw mode.
+/// The terminal is restored by `restore`, which also runs when we panic.
+fn enter() -> io::Result<()> {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        restore();
+        hook(info);
+    }));
+    enable_raw_mode()?;
+    ACTIVE.store(true, Ordering::SeqCst);
+    execute!(io::stdout(), EnterAlternateScreen, Hide)
+}
+
+/// Take over the terminal for a chart, or give up before any probe is sent,
+/// whose samples would have nowhere to go.
+fn enter_or_exit() {
+    if let Err(e) = enter() {
+        restore();
+        error!("Failed to set up the terminal: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Draw without colors, e.g. for `NO_COLOR`.
+pub fn set_monochrome(monochrome: bool) {
+    MONOCHROME.store(monochrome, Ordering::SeqCst);
+}
+
+/// Whether the chart is shown.
+pub fn active() -> bool {
+    ACTIVE.load(Ordering::SeqCst)
+}
+
+/// Leave the alternate screen and raw mode, if we entered them.
+pub fn restore() {
+    if ACTIVE.swap(false, Ordering::SeqCst) {
+        if let Err(e) = disable_raw_mode() {
+            error!("Failed to disable raw mode: {}", e);
+        }
+        if let Err(e) = execute!(io::stdout(), LeaveAlternateScreen, Show) {
+            error!("Failed to leave the alternate screen: {}", e);
+        }
+    }
+}
+
+/// Wait until the user asks to quit from the chart.
+/// Raw mode swallows ctrl-c, so this replaces the signal while the chart is shown.
+pub async fn quit_requested() {
+    QUIT.notified().await
+}
+
+/// Wait while probing is paused from the chart.
+/// Returns whether we had to wait, so callers can restart their timers.
+pub async fn wait_resumed() -> bool {
+    if !PAUSED.load(Ordering::SeqCst) {
+        return false;
+    }
+    loop {
+        let resumed = RESUMED.notified();
+        if !PAUSED.load(Ordering::SeqCst) {
+            return true;
+        }
+        resumed.await;
+    }
+}
+
+fn toggle_pause() {
+    if PAUSED.fetch_xor(true, Ordering::SeqCst) {
+        RESUMED.notify_waiters();
+    }
+}
+
+/// Forward the samples of a single prober as series `series` of a shared chart.
+pub fn forward_series(
+    mut rx: mpsc::Receiver<Sample>,
+    series: usize,
+    tx: mpsc::Sender<(usize, Update)>,
+) {
+    tokio::spawn(async move {
+        while let Some(update) = rx.recv().await {
+            if tx.send((series, Update::Sample(update))).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Draw the latency chart for every update received on `rx`.
+pub fn spawn_graph(rx: mpsc::Receiver<Sample>, data: PlotData) {
+    let (tx, series_rx) = mpsc::channel(10);
+    forward_series(rx, 0, tx);
+    spawn_series_graph(series_rx, vec![data]);
+}
+
+/// Terminal events, read on a thread of their own since crossterm only offers
+/// a blocking reader.
+fn spawn_events() -> mpsc::Receiver<Event> {
+    let (tx, rx) = mpsc::channel(10);
+    thread::spawn(move || loop {
+        match event::read() {
+            Ok(event) => {
+                if tx.blocking_send(event).is_err() {
+                    break;
+                }
+            }
+            Err(e) => {
+                error!("Failed to read terminal event: {}", e);
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// How the latencies are drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Line,
+    Histogram,
+    Heatmap,
+}
+
+impl Mode {
+    fn next(self) -> Self {
+        match self {
+            Mode::Line => Mode::Histogram,
+            Mode::Histogram => Mode::Heatmap,
+            Mode::Heatmap => Mode::Line,
+        }
+    }
+}
+
+/// What the user changed on the chart.
+struct View {
+    mode: Mode,
+    hidden: Vec<bool>,
+    /// How many events are scrolled past, from the newest one.
+    scroll: usize,
+}
+
+impl View {
+    /// Apply a key press, returns whether the chart has to be redrawn.
+    fn handle(&mut self, key: KeyEvent, data: &mut [PlotData]) -> bool {
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                QUIT.notify_one()
+            }
+            KeyCode::Char('q') | KeyCode::Esc => QUIT.notify_one(),
+            KeyCode::Char('p') | KeyCode::Char(' ') => toggle_pause(),
+            KeyCode::Char('r') => data.iter_mut().for_each(PlotData::reset),
+            KeyCode::Char('v') => self.mode = self.mode.next(),
+            code @ (KeyCode::Up
+            | KeyCode::Down
+            | KeyCode::Char('k')
+            | KeyCode::Char('j')
+            | KeyCode::PageUp
+            | KeyCode::PageDown
+            | KeyCode::End) => scroll_events(&mut self.scroll, code),
+            KeyCode::Char('+') | KeyCode::Char('=') => {
+                data.iter_mut().for_each(|series| series.zoom(-1))
+            }
+            KeyCode::Char('-') => data.iter_mut().for_each(|series| series.zoom(1)),
+            KeyCode::Char(c @ '1'..='9') => {
+                let series = c as usize - '1' as usize;
+                match self.hidden.get_mut(series) {
+                    Some(hidden) => *hidden = !*hidden,
+                    None => return false,
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Scroll the event pane, `scroll` counts the events hidden below it.
+fn scroll_events(scroll: &mut usize, code: KeyCode) {
+    let oldest = logger::len().saturating_sub(1);
+    *scroll = match code {
+        KeyCode::Up | KeyCode::Char('k') => (*scroll + 1).min(oldest),
+        KeyCode::Down | KeyCode::Char('j') => scroll.saturating_sub(1),
+        KeyCode::PageUp => (*scroll + EVENTS_HEIGHT as usize).min(oldest),
+        KeyCode::PageDown => scroll.saturating_sub(EVENTS_HEIGHT as usize),
+        _ => 0,
+    }
+}
+
+/// Draw a chart with several series, every update belongs to the series at its index.
+/// The header shows the statistics of the first series.
+pub fn spawn_series_graph(mut rx: mpsc::Receiver<(usize, Update)>, mut data: Vec<PlotData>) {
+    enter_or_exit();
+    let backend = CrosstermBackend::new(io::stdout());
+    let mut terminal = Terminal::new(backend).unwrap();
+    let mut events = spawn_events();
+    let mut view = View {
+        mode: Mode::Line,
+        hidden: vec![false; data.len()],
+        scroll: 0,
+    };
+
+    let mut flush = interval(FLUSH_INTERVAL);
+    flush.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    tokio::spawn(async move {
+        loop {
+            select! {
+                update = rx.recv() => match update {
+                    Some((series, Update::Sample(item))) => {
+                        if !data[series].update(item) {
+                            continue;
+                        }
+                    }
+                    Some((series, Update::Annotation(label))) => data[series].annotate(label),
+                    None => {
+                        // the run is over, show what is still waiting to be merged
+                        let mut changed = false;
+                        for series in data.iter_mut() {
+                            changed |= series.flush();
+                        }
+                        if changed {
+                            terminal.draw(|f| draw(f, &data, &view)).unwrap();
+                        }
+                        break;
+                    }
+                },
+                // merged points are drawn even when no more probes arrive
+                _ = flush.tick() => {
+                    let mut changed = false;
+                    for series in data.iter_mut() {
+                        changed |= series.flush_due();
+                    }
+                    if !changed {
+                        continue;
+                    }
+                }
+                // without a terminal to read from the chart keeps updating
+                Some(event) = events.recv() => match event {
+                    Event::Key(key) => {
+                        if !view.handle(key, &mut data) {
+                            continue;
+                        }
+                    }
+                    // the terminal is resized by `draw`
+                    Event::Resize(..) => {}
+                    _ => continue,
+                },
+                _ = logger::logged() => {}
+            }
+            terminal.draw(|f| draw(f, &data, &view)).unwrap();
+        }
+    });
+}
+
+fn draw(f: &mut Frame<CrosstermBackend<Stdout>>, data: &[PlotData], view: &View) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .vertical_margin(1)
+        .horizontal_margin(0)
+        .constraints(
+            [
+                Constraint::Length(1),
+                Constraint::Min(0),
+                Constraint::Length(EVENTS_HEIGHT),
+                Constraint::Length(1),
+            ]
+            .as_ref(),
+        )
+        .split(f.size());
+
+    draw_header(f, chunks[0], &data[0]);
+    match view.mode {
+        Mode::Line => draw_chart(f, chunks[1], data, view),
+        Mode::Histogram => draw_histograms(f, chunks[1], data, view),
+        Mode::Heatmap => draw_heatmaps(f, chunks[1], data, view),
+    }
+    draw_events(f, chunks[2], view.scroll);
+
+    let range = humantime::format_duration(Duration::from_secs(data[0].range() as u64));
+    let status = match PAUSED.load(Ordering::SeqCst) {
+        true => format!("PAUSED  last {range}  {HELP}"),
+        false => format!("last {range}  {HELP}"),
+    };
+    f.render_widget(
+        Paragraph::new(status).style(Style::default().fg(Color::DarkGray)),
+        chunks[3],
+    );
+    if MONOCHROME.load(Ordering::SeqCst) {
+        f.render_widget(Monochrome, f.size());
+    }
+}
+
+/// Drops the colors of everything drawn before it, other modifiers stay.
+struct Monochrome;
+
+impl Widget for Monochrome {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        for x in area.left()..area.right() {
+            for y in area.top()..area.bottom() {
+                buf.get_mut(x, y).set_fg(Color::Reset).set_bg(Color::Reset);
+            }
+        }
+    }
+}
+
+/// Recent log records, colored by their level.
+fn draw_events(f: &mut Frame<CrosstermBackend<Stdout>>, area: Rect, scroll: usize) {
+    let title = match scroll {
+        0 => "events".to_string(),
+        scroll => format!("events (-{scroll})"),
+    };
+    let block = Block::default().borders(Borders::TOP).title(title);
+    let height = block.inner(area).height as usize;
+    let lines: Vec<Spans> = logger::recent(height, scroll)
+        .into_iter()
+        .map(|event| {
+            let color = match event.level {
+                Level::Error => Color::Red,
+                Level::Warn => Color::Yellow,
+                Level::Info => Color::Reset,
+                Level::Debug | Level::Trace => Color::DarkGray,
+            };
+            // 2022-01-01T12:34:56.789Z
+            let time = humantime::format_rfc3339_millis(event.time).to_string();
+            Spans::from(vec![
+                Span::styled(
+                    format!("{} ", &time[11..23]),
+                    Style::default().fg(Color::DarkGray),
+                ),
+                Span::styled(
+                    format!("{:<5} {}", event.level, event.message),
+                    Style::default().fg(color),
+                ),
+            ])
+        })
+        .collect();
+    f.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+fn draw_header(f: &mut Frame<CrosstermBackend<Stdout>>, area: Rect, data: &PlotData) {
+    let header_layout = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(
+            [
+                Constraint::Percentage(28),
+                Constraint::Percentage(12),
+                Constraint::Percentage(12),
+                Constraint::Percentage(12),
+                Constraint::Percentage(12),
+                Constraint::Percentage(12),
+                Constraint::Percentage(12),
+            ]
+            .as_ref(),
+        )
+        .split(area);
+
+    for (area, paragraph) in header_layout.into_iter().zip(data.header_stats()) {
+        f.render_widget(paragraph, area);
+    }
+}
+
+/// The series which are not toggled off.
+fn visible<'a>(data: &'a [PlotData], view: &View) -> Vec<&'a PlotData> {
+    data.iter()
+        .zip(&view.hidden)
+        .filter(|(_, hidden)| !**hidden)
+        .map(|(series, _)| series)
+        .collect()
+}
+
+/// Split `area` into one row per series.
+fn series_areas(area: Rect, series: usize) -> Vec<Rect> {
+    let constraints = vec![Constraint::Ratio(1, series.max(1) as u32); series];
+    Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(constraints)
+        .split(area)
+}
+
+/// Short latency for labels, which have little room.
+fn format_latency(micros: f64) -> String {
+    if micros < 1000.0 {
+        format!("{micros:.0}µs")
+    } else if micros < 1_000_000.0 {
+        format!("{:.1}ms", micros / 1000.0)
+    } else {
+        format!("{:.2}s", micros / 1_000_000.0)
+    }
+}
+
+fn draw_histograms(
+    f: &mut Frame<CrosstermBackend<Stdout>>,
+    area: Rect,
+    data: &[PlotData],
+    view: &View,
+) {
+    let visible = visible(data, view);
+    for (series, area) in visible.iter().zip(series_areas(area, visible.len())) {
+        // every bar needs room for its label
+        let buckets = (area.width / 8).clamp(1, 30);
+        let histogram = series.histogram(buckets as usize);
+        let labels: Vec<String> = histogram
+            .iter()
+            .map(|(lower, _)| format_latency(*lower))
+            .collect();
+        let bars: Vec<(&str, u64)> = labels
+            .iter()
+            .zip(&histogram)
+            .map(|(label, (_, count))| (label.as_str(), *count))
+            .collect();
+        let chart = BarChart::default()
+            .block(Block::default().title(series.display.clone()))
+            .data(&bars)
+            .bar_width((area.width / buckets).saturating_sub(1).max(1))
+            .bar_gap(1)
+            .bar_style(series.style)
+            .value_style(Style::default().fg(Color::Black).bg(Color::Gray));
+        f.render_widget(chart, area);
+    }
+}
+
+fn draw_heatmaps(
+    f: &mut Frame<CrosstermBackend<Stdout>>,
+    area: Rect,
+    data: &[PlotData],
+    view: &View,
+) {
+    let visible = visible(data, view);
+    let bounds = visible
+        .iter()
+        .map(|series| series.y_axis_bounds())
+        .reduce(|a, b| [a[0].min(b[0]), a[1].max(b[1])])
+        .unwrap_or([0.0, 1.0]);
+    for (series, area) in visible.iter().zip(series_areas(area, visible.len())) {
+        let title = format!(
+            "{} {} - {}",
+            series.display,
+            format_latency(series.y_axis.unplot(bounds[0])),
+            format_latency(series.y_axis.unplot(bounds[1]))
+        );
+        let block = Block::default().title(title);
+        let inner = block.inner(area);
+        f.render_widget(block, area);
+        f.render_widget(
+            Heatmap {
+                columns: series.heatmap(inner.width as usize, inner.height as usize, bounds),
+            },
+            inner,
+        );
+    }
+}
+
+/// SmokePing style latency distribution over time.
+/// Every column is a time slice, shaded by how many replies fall into each latency bin,
+/// with its median colored by the loss in that slice.
+struct Heatmap {
+    columns: Vec<HeatmapColumn>,
+}
+
+impl Widget for Heatmap {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        for (x, column) in (area.left()..area.right()).zip(self.columns) {
+            if column.replies == 0 {
+                if column.lost > 0 {
+                    for y in area.top()..area.bottom() {
+                        buf.get_mut(x, y).set_symbol("░").set_fg(Color::Red);
+                    }
+                }
+                continue;
+            }
+            let densest = column.bins.iter().copied().max().unwrap_or(0).max(1);
+            // the lowest latency is at the bottom
+            for (y, count) in (area.top()..area.bottom()).rev().zip(&column.bins) {
+                let symbol = match count * 3 / densest {
+                    0 if *count == 0 => continue,
+                    0 => "░",
+                    1 => "▒",
+                    _ => "▓",
+                };
+                buf.get_mut(x, y).set_symbol(symbol).set_fg(Color::Gray);
+            }
+            if let Some(median) = column.median {
+                let loss = column.lost as f64 / (column.lost + column.replies) as f64;
+                let color = if loss == 0.0 {
+                    Color::Green
+                } else if loss < 0.2 {
+                    Color::Yellow
+                } else {
+                    Color::Red
+                };
+                buf.get_mut(x, area.bottom() - 1 - median as u16)
+                    .set_symbol("█")
+                    .set_fg(color);
+            }
+        }
+    }
+}
+
+fn draw_chart(f: &mut Frame<CrosstermBackend<Stdout>>, area: Rect, data: &[PlotData], view: &View) {
+    let visible = visible(data, view);
+
+    let y_axis_bounds = visible
+        .iter()
+        .map(|series| series.y_axis_bounds())
+        .reduce(|a, b| [a[0].min(b[0]), a[1].max(b[1])])
+        .unwrap_or([0.0, 1.0]);
+    // hidden series still move the time axis, so toggling them doesn't shift it
+    let x_axis_bounds = data
+        .iter()
+        .map(|series| series.x_axis_bounds())
+        .max_by(|a, b| a[1].total_cmp(&b[1]))
+        .unwrap();
+
+    // annotations are vertical lines over the whole chart
+    let annotations: Vec<(&str, [(f64, f64); 2])> = visible
+        .iter()
+        .flat_map(|series| series.annotations())
+        .map(|(x, label)| {
+            (
+                label.as_str(),
+                [(*x, y_axis_bounds[0]), (*x, y_axis_bounds[1])],
+            )
+        })
+        .collect();
+
+    // losses are marked at the top of the chart, as they have no latency
+    let losses = |outcome: Outcome| -> Vec<(f64, f64)> {
+        visible
+            .iter()
+            .flat_map(|series| series.losses())
+            .filter(|(_, loss)| *loss == outcome)
+            .map(|(x, _)| (x, y_axis_bounds[1]))
+            .collect()
+    };
+    let markers = [
+        ("timeout", Color::Red, losses(Outcome::Timeout)),
+        ("error", Color::Magenta, losses(Outcome::Error)),
+    ];
+
+    // aggregated ranges also show the lowest and highest latency of every bucket
+    let envelopes: Vec<_> = visible
+        .iter()
+        .filter_map(|series| {
+            let envelope = series.envelope()?.map(|points| series.plot(points));
+            Some((series.marker, envelope))
+        })
+        .collect();
+    let points: Vec<_> = visible.iter().map(|series| series.points()).collect();
+    let mut datasets: Vec<Dataset> = envelopes
+        .iter()
+        .flat_map(|(marker, envelope)| envelope.iter().map(move |points| (*marker, points)))
+        .map(|(marker, points)| {
+            Dataset::default()
+                .marker(marker)
+                .style(Style::default().fg(Color::DarkGray))
+                .graph_type(GraphType::Line)
+                .data(points)
+        })
+        .collect();
+    datasets.extend(
+        visible
+            .iter()
+            .zip(&points)
+            .map(|(series, points)| match data.len() {
+                1 => series.dataset(points),
+                _ => series.dataset(points).name(series.display.clone()),
+            }),
+    );
+    let overlays: Vec<_> = visible
+        .iter()
+        .flat_map(|series| {
+            series.overlays().into_iter().map(|(overlay, line)| {
+                let name = match data.len() {
+                    1 => overlay.to_string(),
+                    _ => format!("{} {overlay}", series.display),
+                };
+                (series.marker, overlay, name, line)
+            })
+        })
+        .collect();
+    datasets.extend(overlays.iter().map(|(marker, overlay, name, line)| {
+        let color = match overlay {
+            Overlay::Average => Color::LightCyan,
+            Overlay::Ewma => Color::LightMagenta,
+            Overlay::Percentile(50) => Color::LightGreen,
+            Overlay::Percentile(95) => Color::LightYellow,
+            Overlay::Percentile(_) => Color::LightRed,
+            Overlay::Slo => Color::Red,
+        };
+        Dataset::default()
+            .name(name.clone())
+            .marker(*marker)
+            .style(Style::default().fg(color))
+            .graph_type(GraphType::Line)
+            .data(line)
+    }));
+    datasets.extend(annotations.iter().map(|(label, line)| {
+        Dataset::default()
+            .name(*label)
+            .marker(data[0].marker)
+            .style(Style::default().fg(Color::Yellow))
+            .graph_type(GraphType::Line)
+            .data(line)
+    }));
+    datasets.extend(
+        markers
+            .iter()
+            .filter(|(_, _, points)| !points.is_empty())
+            .map(|(name, color, points)| {
+                Dataset::default()
+                    .name(*name)
+                    .marker(symbols::Marker::Dot)
+                    .style(Style::default().fg(*color))
+                    .graph_type(GraphType::Scatter)
+                    .data(points)
+            }),
+    );
+
+    let chart = Chart::new(datasets)
+        .block(Block::default().borders(Borders::NONE))
+        .x_axis(
+            Axis::default()
+                .style(Style::default().fg(Color::Gray))
+                .bounds(x_axis_bounds)
+                .labels(data[0].x_axis_labels(x_axis_bounds)),
+        )
+        .y_axis(
+            Axis::default()
+                .style(Style::default().fg(Color::Gray))
+                .bounds(y_axis_bounds)
+                .labels(data[0].y_axis_labels(y_axis_bounds)),
+        );
+
+    f.render_widget(chart, area);
+}
+
+/// Forward the hops of a single traceroute as target `target` of a shared view,
+/// with the reverse lookup of every address that shows up.
+pub fn forward_trace(
+    mut rx: mpsc::Receiver<TraceUpdate>,
+    target: usize,
+    tx: mpsc::Sender<(usize, TraceUpdate)>,
+) {
+    tokio::spawn(async move {
+        let mut looked_up = HashSet::new();
+        while let Some(update) = rx.recv().await {
+            if let TraceUpdate::Hop(_, Some((addr, _))) = update {
+                if looked_up.insert(addr) {
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        let name = tokio::task::spawn_blocking(move || lookup_addr(&addr.into()))
+                            .await
+                            .unwrap();
+                        match name {
+                            // without a PTR record the address comes back as is
+                            Ok(name) if name != addr.to_string() => {
+                                let _ = tx.send((target, TraceUpdate::Name(addr, name))).await;
+                            }
+                            _ => {}
+                        }
+                    });
+                }
+            }
+            if tx.send((target, update)).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Draw the hops of every target, updates belong to the target at their index.
+pub fn spawn_trace_graph(mut rx: mpsc::Receiver<(usize, TraceUpdate)>, mut data: Vec<TraceData>) {
+    enter_or_exit();
+    let backend = CrosstermBackend::new(io::stdout());
+    let mut terminal = Terminal::new(backend).unwrap();
+    let mut events = spawn_events();
+    let mut scroll = 0;
+
+    tokio::spawn(async move {
+        loop {
+            select! {
+                update = rx.recv() => match update {
+                    Some((target, update)) => data[target].update(update),
+                    None => break,
+                },
+                Some(event) = events.recv() => match event {
+                    Event::Key(key) => match key.code {
+                        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            QUIT.notify_one()
+                        }
+                        KeyCode::Char('q') | KeyCode::Esc => QUIT.notify_one(),
+                        KeyCode::Char('p') | KeyCode::Char(' ') => toggle_pause(),
+                        KeyCode::Char('r') => data.iter_mut().for_each(TraceData::reset),
+                        code @ (KeyCode::Up
+                        | KeyCode::Down
+                        | KeyCode::Char('k')
+                        | KeyCode::Char('j')
+                        | KeyCode::PageUp
+                        | KeyCode::PageDown
+                        | KeyCode::End) => scroll_events(&mut scroll, code),
+                        _ => continue,
+                    },
+                    Event::Resize(..) => {}
+                    _ => continue,
+                },
+                _ = logger::logged() => {}
+            }
+            terminal.draw(|f| draw_trace(f, &data, scroll)).unwrap();
+        }
+    });
+}
+
+fn draw_trace(f: &mut Frame<CrosstermBackend<Stdout>>, data: &[TraceData], scroll: usize) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .vertical_margin(1)
+        .constraints(
+            [
+                Constraint::Min(0),
+                Constraint::Length(EVENTS_HEIGHT),
+                Constraint::Length(1),
+            ]
+            .as_ref(),
+        )
+        .split(f.size());
+
+    for (target, area) in data.iter().zip(series_areas(chunks[0], data.len())) {
+        draw_hops(f, area, target);
+    }
+    draw_events(f, chunks[1], scroll);
+
+    let status = match PAUSED.load(Ordering::SeqCst) {
+        true => format!("PAUSED  {TRACE_HELP}"),
+        false => TRACE_HELP.to_string(),
+    };
+    f.render_widget(
+        Paragraph::new(status).style(Style::default().fg(Color::DarkGray)),
+        chunks[2],
+    );
+    if MONOCHROME.load(Ordering::SeqCst) {
+        f.render_widget(Monochrome, f.size());
+    }
+}
+
+/// One row per hop: TTL, address, name, loss, last round trip and a sparkline of recent ones.
+fn draw_hops(f: &mut Frame<CrosstermBackend<Stdout>>, area: Rect, data: &TraceData) {
+    let block = Block::default().title(data.display.clone());
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+
+    let columns = |row: Rect| {
+        Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints(
+                [
+                    Constraint::Length(4),
+                    Constraint::Length(16),
+                    Constraint::Percentage(30),
+                    Constraint::Length(7),
+                    Constraint::Length(9),
+                    Constraint::Min(0),
+                ]
+                .as_ref(),
+            )
+            .split(row)
+    };
+    let row = |y: u16| Rect {
+        y: inner.y + y,
+        height: 1,
+        ..inner
+    };
+    if inner.height == 0 {
+        return;
+    }
+
+    let header = Style::default().fg(Color::DarkGray);
+    for (area, title) in columns(row(0))
+        .into_iter()
+        .zip(["ttl", "address", "host", "loss", "last", "recent"])
+    {
+        f.render_widget(Paragraph::new(title).style(header), area);
+    }
+
+    let max = data.max_rtt();
+    for (ttl, hop) in data.hops.iter().enumerate() {
+        let y = ttl as u16 + 1;
+        if y >= inner.height {
+            break;
+        }
+        let cells = columns(row(y));
+        let addr = hop.addr.map_or("*".to_string(), |addr| addr.to_string());
+        let name = hop.name.clone().unwrap_or_default();
+        let last = hop.last().map_or(
+            "*".to_string(),
+            |rtt| format_latency(rtt.as_micros() as f64),
+        );
+        let loss_style = match hop.received {
+            received if received == hop.sent => Style::default(),
+            0 => Style::default().fg(Color::Red),
+            _ => Style::default().fg(Color::Yellow),
+        };
+        f.render_widget(Paragraph::new(format!("{:>2}", ttl + 1)), cells[0]);
+        f.render_widget(Paragraph::new(addr), cells[1]);
+        f.render_widget(Paragraph::new(name), cells[2]);
+        f.render_widget(
+            Paragraph::new(format!("{:.0}%", hop.loss())).style(loss_style),
+            cells[3],
+        );
+        f.render_widget(Paragraph::new(last), cells[4]);
+
+        // the newest round trips are on the right
+        let history = hop.sparkline();
+        let shown = &history[history.len().saturating_sub(cells[5].width as usize)..];
+        f.render_widget(
+            Sparkline::default()
+                .data(shown)
+                .max(max)
+                .style(Style::default().fg(Color::Cyan)),
+            cells[5],
+        );
+    }
+}